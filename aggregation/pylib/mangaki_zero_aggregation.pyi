@@ -13,7 +13,7 @@ class PublicKeysWrapper:
     def insert(self, u: int, pk: SignPublicKey) -> None: ...
 
 class UserWrapper:
-    def __new__(cls, id: int, threshold: int, sign_pk: SignPublicKey, sign_sk: SignSecretKey, vec: list[int], others_sign_pks: PublicKeysWrapper) -> 'UserWrapper': ...
+    def __new__(cls, id: int, threshold: int, vec_len: int, sign_pk: SignPublicKey, sign_sk: SignSecretKey, vec: list[int], others_sign_pks: PublicKeysWrapper, scale: float, clip: float) -> 'UserWrapper': ...
     def serialize_state(self) -> str: ...
     def recover_state(self, state: str) -> None: ...
     def round(self, input: bytes) -> bytes: ...
@@ -24,13 +24,14 @@ class ServerOutputWrapper:
     def is_vector(self) -> bool: ...
     def get_messages(self, arg: Any) -> Dict[int, bytes]: ...
     def get_vector(self) -> list[int]: ...
+    def get_users(self) -> list[int]: ...
 
 class ServerWrapper:
-    def __new__(cls, threshold: int, vec_len: int) -> 'ServerWrapper': ...
+    def __new__(cls, threshold: int, vec_len: int, sign_pks: PublicKeysWrapper, scale: float, clip: float) -> 'ServerWrapper': ...
     def serialize_state(self) -> str: ...
     def recover_state(self, state: str) -> None: ...
-    def recv(self, id: int, input: list[int]) -> None: ...
+    def recv(self, id: int, input: bytes) -> None: ...
     def round(self) -> ServerOutputWrapper: ...
 
-def round0_msg(msg: Any) -> bytes: ...
+def round0_msg() -> bytes: ...
 def gen_keypair() -> Tuple[SignPublicKey, SignSecretKey]: ...
@@ -7,11 +7,19 @@ use pyo3::prelude::*;
 use pyo3::types::PyBytes;
 use pyo3::exceptions;
 
+use aggregation::error::*;
 use aggregation::sodium_bindings::*;
+use aggregation::cohort::SessionId;
+use aggregation::encoding::FixedPointEncoding;
+use aggregation::wire::{self, Header, Sender};
 use aggregation::types::*;
 use aggregation::user::*;
 use aggregation::server::*;
 
+fn to_py_err(e: AggregationError) -> PyErr {
+    PyErr::new::<exceptions::PyIOError, _>(e.to_string())
+}
+
 #[pyclass]
 #[derive(Clone)]
 struct PublicKeysWrapper(Arc<BTreeMap<usize, SignPublicKey>>);
@@ -42,36 +50,45 @@ impl UserWrapper {
     pub fn new(
         id: usize,
         threshold: usize,
+        vec_len: usize,
         sign_pk: SignPublicKey,
-        sign_sk: SignSecretKey,
+        sign_sk: Vec<u8>,
         vec: Vec<i64>,
         others_sign_pks: PublicKeysWrapper,
-    ) -> Self {
-        UserWrapper(User::new(
-            id, threshold, sign_pk, sign_sk,
+        scale: f64,
+        clip: f64,
+    ) -> PyResult<Self> {
+        let sign_sk = SignSecretKey::from_slice(&sign_sk).map_err(to_py_err)?;
+        let encoding = FixedPointEncoding::new(scale, clip).map_err(to_py_err)?;
+        match User::new(
+            id, threshold, vec_len, sign_pk, sign_sk,
             vec.into_iter().map(Wrapping).collect(),
-            others_sign_pks.0
-        ))
+            others_sign_pks.0,
+            &encoding
+        ) {
+            Ok(user) => Ok(UserWrapper(user)),
+            Err(e) => Err(to_py_err(e))
+        }
     }
 
     pub fn serialize_state(self_: PyRef<Self>) -> PyResult<String> {
         match self_.0.serialize_state() {
             Ok(s) => Ok(s),
-            Err(_) => Err(PyErr::new::<exceptions::PyIOError, _>(()))
+            Err(e) => Err(to_py_err(e))
         }
     }
 
     pub fn recover_state(mut self_: PyRefMut<Self>, s: &str) -> PyResult<()> {
         match self_.0.recover_state(s) {
             Ok(()) => Ok(()),
-            Err(_) => Err(PyErr::new::<exceptions::PyIOError, _>(()))
+            Err(e) => Err(to_py_err(e))
         }
     }
 
     pub fn round<'a>(mut self_: PyRefMut<Self>, py: Python<'a>, input: &[u8]) -> PyResult<&'a PyBytes> {
         match self_.0.round_serialized(input) {
             Ok(output) => Ok(PyBytes::new(py, &output)),
-            Err(_) => Err(PyErr::new::<exceptions::PyIOError, _>(()))
+            Err(e) => Err(to_py_err(e))
         }
     }
 }
@@ -96,7 +113,7 @@ impl ServerOutputWrapper {
     
     pub fn is_vector(self_: PyRef<Self>) -> bool {
         match &self_.0 {
-            ServerOutputSerialized::Vector(_) => true,
+            ServerOutputSerialized::Aggregate(_) => true,
             _ => false,
         }
     }
@@ -111,7 +128,14 @@ impl ServerOutputWrapper {
 
     pub fn get_vector(self_: PyRef<Self>) -> PyResult<Vec<i64>> {
         match &self_.0 {
-            ServerOutputSerialized::Vector(v) => Ok(v.iter().map(|Wrapping(i)| *i).collect()),
+            ServerOutputSerialized::Aggregate(a) => Ok(a.vec.iter().map(|Wrapping(i)| *i).collect()),
+            _ => Err(PyErr::new::<exceptions::PyTypeError, _>(()))
+        }
+    }
+
+    pub fn get_users(self_: PyRef<Self>) -> PyResult<Vec<usize>> {
+        match &self_.0 {
+            ServerOutputSerialized::Aggregate(a) => Ok(a.users.iter().cloned().collect()),
             _ => Err(PyErr::new::<exceptions::PyTypeError, _>(()))
         }
     }
@@ -125,47 +149,54 @@ struct ServerWrapper {
 #[pymethods]
 impl ServerWrapper {
     #[new]
-    pub fn new(threshold: usize, vec_len: usize) -> Self {
-        ServerWrapper { wrapped: Server::new(threshold, vec_len) }
+    pub fn new(threshold: usize, vec_len: usize, sign_pks: PublicKeysWrapper, scale: f64, clip: f64) -> PyResult<Self> {
+        let encoding = FixedPointEncoding::new(scale, clip).map_err(to_py_err)?;
+        match Server::new(threshold, vec_len, sign_pks.0, &encoding) {
+            Ok(server) => Ok(ServerWrapper { wrapped: server }),
+            Err(e) => Err(to_py_err(e))
+        }
     }
 
     pub fn serialize_state(self_: PyRef<Self>) -> PyResult<String> {
         match self_.wrapped.serialize_state() {
             Ok(s) => Ok(s),
-            Err(_) => Err(PyErr::new::<exceptions::PyIOError, _>(()))
+            Err(e) => Err(to_py_err(e))
         }
     }
 
     pub fn recover_state(mut self_: PyRefMut<Self>, s: &str) -> PyResult<()> {
         match self_.wrapped.recover_state(s) {
             Ok(()) => Ok(()),
-            Err(_) => Err(PyErr::new::<exceptions::PyIOError, _>(()))
+            Err(e) => Err(to_py_err(e))
         }
     }
 
     pub fn recv<'a>(mut self_: PyRefMut<Self>, id: usize, input: &[u8]) -> PyResult<()> {
         match self_.wrapped.recv_serialized(id, input) {
             Ok(()) => Ok(()),
-            Err(_) => Err(PyErr::new::<exceptions::PyIOError, _>(()))
+            Err(e) => Err(to_py_err(e))
         }
     }
 
     pub fn round<'a>(mut self_: PyRefMut<Self>) -> PyResult<ServerOutputWrapper> {
         match self_.wrapped.round_serialized() {
             Ok(output) => Ok(ServerOutputWrapper::new(output)),
-            Err(_) => Err(PyErr::new::<exceptions::PyIOError, _>(()))
+            Err(e) => Err(to_py_err(e))
         }
     }
 }
 
 #[pyfunction]
 fn round0_msg<'a>(py: Python<'a>) -> &'a PyBytes {
-    PyBytes::new(py, &bincode::serialize(&UserInput::Round0()).unwrap())
+    let header = Header::new(SessionId::default(), Some(0), Sender::Server);
+    PyBytes::new(py, &wire::encode(&header, &UserInput::Round0()).unwrap())
 }
 
 #[pyfunction]
-fn gen_keypair() -> (SignPublicKey, SignSecretKey) {
-    gen_sign_keypair()
+fn gen_keypair() -> (SignPublicKey, Vec<u8>) {
+    // Python cannot wipe the secret key anyway.
+    let (pk, sk) = gen_sign_keypair();
+    (pk, sk.expose().to_vec())
 }
 
 #[pymodule]
@@ -19,9 +19,9 @@ class TestMain:
         for (u, (pk, _)) in enumerate(sign_keys):
             sign_pks.insert(ids[u], pk)
 
-        users = [ UserWrapper(ids[u], threshold, pk, sk, [(u + 1 if i == u else 0) for i in range(vec_len)], sign_pks)
+        users = [ UserWrapper(ids[u], threshold, vec_len, pk, sk, [(u + 1 if i == u else 0) for i in range(vec_len)], sign_pks, 1., 1000.)
                     for (u, (pk, sk)) in enumerate(sign_keys) ]
-        server = ServerWrapper(threshold, vec_len)
+        server = ServerWrapper(threshold, vec_len, sign_pks, 1., 1000.)
 
         msgs = {ids[i]: round0_msg() for i in range(participants)}
         server_output = None
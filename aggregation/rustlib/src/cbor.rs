@@ -0,0 +1,282 @@
+use std::collections::BTreeMap;
+
+use ciborium::value::Value;
+
+use crate::ring::{self, Ring};
+use crate::helpers::*;
+use crate::shamir::Share;
+use crate::types::{UserInput, UserOutput};
+use crate::error::*;
+
+// The messages of the protocol in CBOR (RFC 8949), for clients which are
+// not written in Rust. The schema, in CDDL, is in `schema/messages.cddl`,
+// and `tests/vectors/messages.json` holds messages for other
+// implementations to check theirs against.
+//
+// Encodings are deterministic: integers and lengths take as few bytes as
+// possible, and map keys come in increasing order. Decoding refuses any
+// other encoding, so that every message has exactly one.
+
+pub trait Cbor: Sized {
+    fn to_cbor(&self) -> Value;
+    fn from_cbor(value: Value) -> Option<Self>;
+}
+
+pub fn encode<T: Cbor>(x: &T) -> Result<Vec<u8>, AggregationError> {
+    encode_value(&x.to_cbor())
+}
+
+pub fn decode<T: Cbor>(bytes: &[u8]) -> Result<T, AggregationError> {
+    let value: Value = ciborium::de::from_reader(bytes).map_err(|_| ErrorKind::MalformedMessage)?;
+    if encode_value(&value)? != bytes {
+        return Err(ErrorKind::MalformedMessage.into())
+    }
+    T::from_cbor(value).ok_or_else(|| ErrorKind::MalformedMessage.into())
+}
+
+fn encode_value(value: &Value) -> Result<Vec<u8>, AggregationError> {
+    let mut bytes = Vec::new();
+    ciborium::ser::into_writer(value, &mut bytes).map_err(|_| ErrorKind::Serialization)?;
+    Ok(bytes)
+}
+
+fn uint(x: u64) -> Value {
+    Value::Integer(x.into())
+}
+
+fn as_uint(value: Value) -> Option<u64> {
+    match value {
+        Value::Integer(x) => u64::try_from(x).ok(),
+        _ => None,
+    }
+}
+
+fn as_bytes(value: Value) -> Option<Vec<u8>> {
+    match value {
+        Value::Bytes(bytes) => Some(bytes),
+        _ => None,
+    }
+}
+
+fn as_array<const N: usize>(value: Value) -> Option<[Value; N]> {
+    match value {
+        Value::Array(values) => values.try_into().ok(),
+        _ => None,
+    }
+}
+
+// Variants are arrays whose first element tells which one they are.
+fn variant(tag: u64, fields: Vec<Value>) -> Value {
+    Value::Array([vec![uint(tag)], fields].concat())
+}
+
+fn as_variant(value: Value) -> Option<(u64, Vec<Value>)> {
+    match value {
+        Value::Array(mut values) if !values.is_empty() => {
+            let tag = as_uint(values.remove(0))?;
+            Some((tag, values))
+        },
+        _ => None,
+    }
+}
+
+fn only<T: Cbor>(fields: Vec<Value>) -> Option<T> {
+    let [x] = fields.try_into().ok()?;
+    T::from_cbor(x)
+}
+
+impl Cbor for u64 {
+    fn to_cbor(&self) -> Value {
+        uint(*self)
+    }
+
+    fn from_cbor(value: Value) -> Option<Self> {
+        as_uint(value)
+    }
+}
+
+impl Cbor for usize {
+    fn to_cbor(&self) -> Value {
+        uint(*self as u64)
+    }
+
+    fn from_cbor(value: Value) -> Option<Self> {
+        usize::try_from(as_uint(value)?).ok()
+    }
+}
+
+// Keys, signatures and nonces.
+impl<const N: usize> Cbor for [u8; N] {
+    fn to_cbor(&self) -> Value {
+        Value::Bytes(self.to_vec())
+    }
+
+    fn from_cbor(value: Value) -> Option<Self> {
+        as_bytes(value)?.try_into().ok()
+    }
+}
+
+impl<T: Cbor> Cbor for Vec<T> {
+    fn to_cbor(&self) -> Value {
+        Value::Array(self.iter().map(T::to_cbor).collect())
+    }
+
+    fn from_cbor(value: Value) -> Option<Self> {
+        match value {
+            Value::Array(values) => values.into_iter().map(T::from_cbor).collect(),
+            _ => None,
+        }
+    }
+}
+
+impl<A: Cbor, B: Cbor> Cbor for (A, B) {
+    fn to_cbor(&self) -> Value {
+        Value::Array(vec![self.0.to_cbor(), self.1.to_cbor()])
+    }
+
+    fn from_cbor(value: Value) -> Option<Self> {
+        let [a, b] = as_array(value)?;
+        Some((A::from_cbor(a)?, B::from_cbor(b)?))
+    }
+}
+
+// Maps from the ids of users.
+impl<T: Cbor> Cbor for BTreeMap<usize, T> {
+    fn to_cbor(&self) -> Value {
+        Value::Map(self.iter().map(|(k, v)| (k.to_cbor(), v.to_cbor())).collect())
+    }
+
+    fn from_cbor(value: Value) -> Option<Self> {
+        let Value::Map(entries) = value else { return None };
+        let mut m = BTreeMap::new();
+        for (k, v) in entries {
+            let k = usize::from_cbor(k)?;
+            if m.last_key_value().is_some_and(|(last, _)| *last >= k) {
+                return None
+            }
+            m.insert(k, T::from_cbor(v)?);
+        }
+        Some(m)
+    }
+}
+
+impl<T: Signable + Cbor> Cbor for Signed<T> {
+    fn to_cbor(&self) -> Value {
+        Value::Array(vec![self.msg().to_cbor(), self.sig().to_cbor()])
+    }
+
+    fn from_cbor(value: Value) -> Option<Self> {
+        let [msg, sig] = as_array(value)?;
+        Some(Signed::from_parts(T::from_cbor(msg)?, Cbor::from_cbor(sig)?))
+    }
+}
+
+impl Cbor for CryptoMsg {
+    fn to_cbor(&self) -> Value {
+        Value::Array(vec![self.nonce.to_cbor(), Value::Bytes(self.c.clone())])
+    }
+
+    fn from_cbor(value: Value) -> Option<Self> {
+        let [nonce, c] = as_array(value)?;
+        Some(CryptoMsg { nonce: Cbor::from_cbor(nonce)?, c: as_bytes(c)? })
+    }
+}
+
+impl Cbor for BundledSignature {
+    fn to_cbor(&self) -> Value {
+        self.sig.to_cbor()
+    }
+
+    fn from_cbor(value: Value) -> Option<Self> {
+        Some(BundledSignature::new(Cbor::from_cbor(value)?))
+    }
+}
+
+impl Cbor for Share {
+    fn to_cbor(&self) -> Value {
+        Value::Array(vec![uint(self.point), self.values.to_cbor()])
+    }
+
+    fn from_cbor(value: Value) -> Option<Self> {
+        let [point, values] = as_array(value)?;
+        Some(Share { point: as_uint(point)?, values: Cbor::from_cbor(values)? })
+    }
+}
+
+impl Cbor for RevealedShare {
+    fn to_cbor(&self) -> Value {
+        match self {
+            RevealedShare::RandSk(share) => variant(0, vec![share.to_cbor()]),
+            RevealedShare::Seed(share) => variant(1, vec![share.to_cbor()]),
+        }
+    }
+
+    fn from_cbor(value: Value) -> Option<Self> {
+        match as_variant(value)? {
+            (0, fields) => Some(RevealedShare::RandSk(only(fields)?)),
+            (1, fields) => Some(RevealedShare::Seed(only(fields)?)),
+            _ => None,
+        }
+    }
+}
+
+// Tagged with the round they are meant for, and `NextIteration` with 5.
+impl Cbor for UserInput {
+    fn to_cbor(&self) -> Value {
+        match self {
+            UserInput::Round0() => variant(0, vec![]),
+            UserInput::Round1(keys) => variant(1, vec![keys.to_cbor()]),
+            UserInput::Round2(msgs) => variant(2, vec![msgs.to_cbor()]),
+            UserInput::Round3(users) => variant(3, vec![users.to_cbor()]),
+            UserInput::Round4(sigs) => variant(4, vec![sigs.to_cbor()]),
+            UserInput::NextIteration(users) => variant(5, vec![users.to_cbor()]),
+        }
+    }
+
+    fn from_cbor(value: Value) -> Option<Self> {
+        match as_variant(value)? {
+            (0, fields) if fields.is_empty() => Some(UserInput::Round0()),
+            (1, fields) => Some(UserInput::Round1(only(fields)?)),
+            (2, fields) => Some(UserInput::Round2(only(fields)?)),
+            (3, fields) => Some(UserInput::Round3(only(fields)?)),
+            (4, fields) => Some(UserInput::Round4(only(fields)?)),
+            (5, fields) => Some(UserInput::NextIteration(only(fields)?)),
+            _ => None,
+        }
+    }
+}
+
+// Tagged with the round they are sent in. Masked inputs are packed as in
+// `ring::pack`.
+impl<R: Ring> Cbor for UserOutput<R> {
+    fn to_cbor(&self) -> Value {
+        match self {
+            UserOutput::Round0(comm_pk, rand_pk) => variant(0, vec![comm_pk.to_cbor(), rand_pk.to_cbor()]),
+            UserOutput::Round1(msgs) => variant(1, vec![msgs.to_cbor()]),
+            UserOutput::Round2(vec) => {
+                let (len, bytes) = ring::pack(vec);
+                variant(2, vec![Value::Array(vec![uint(len), Value::Bytes(bytes)])])
+            },
+            UserOutput::Round3(sig) => variant(3, vec![sig.to_cbor()]),
+            UserOutput::Round4(shares) => variant(4, vec![shares.to_cbor()]),
+        }
+    }
+
+    fn from_cbor(value: Value) -> Option<Self> {
+        match as_variant(value)? {
+            (0, fields) => {
+                let [comm_pk, rand_pk] = fields.try_into().ok()?;
+                Some(UserOutput::Round0(Signed::from_cbor(comm_pk)?, Signed::from_cbor(rand_pk)?))
+            },
+            (1, fields) => Some(UserOutput::Round1(only(fields)?)),
+            (2, fields) => {
+                let [packed] = fields.try_into().ok()?;
+                let [len, bytes] = as_array(packed)?;
+                Some(UserOutput::Round2(ring::unpack(as_uint(len)?, &as_bytes(bytes)?).ok()?))
+            },
+            (3, fields) => Some(UserOutput::Round3(only(fields)?)),
+            (4, fields) => Some(UserOutput::Round4(only(fields)?)),
+            _ => None,
+        }
+    }
+}
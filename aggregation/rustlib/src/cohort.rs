@@ -0,0 +1,124 @@
+use std::collections::BTreeMap;
+
+use serde::{Serialize, Deserialize};
+
+use crate::sodium_bindings::*;
+use crate::helpers::*;
+use crate::shamir;
+use crate::error::*;
+
+// Registration -- Before Round 0, users send their identity key to a
+// coordinator, who publishes the cohort of the session in a manifest signed
+// with its own key. Users only take part in a session whose manifest they
+// could verify, and take the keys of the other members from it rather than
+// from an out-of-band channel.
+
+pub type SessionId = [u8; 16];
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Registration {
+    pub session_id: SessionId,
+    pub id: usize,
+    pub sign_pk: SignPublicKey,
+}
+
+// Signed with the secret key of the user, to prove that it owns `sign_pk`.
+impl Signable for Registration {
+    fn as_message(&self) -> Vec<u8> {
+        let mut m = b"registration".to_vec();
+        m.extend_from_slice(&self.session_id);
+        m.extend_from_slice(&(self.id as u64).to_le_bytes());
+        m.extend_from_slice(&self.sign_pk);
+        m
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub session_id: SessionId,
+    pub threshold: usize,
+    pub vec_len: usize,
+    pub members: BTreeMap<usize, SignPublicKey>,
+}
+
+impl Signable for Manifest {
+    fn as_message(&self) -> Vec<u8> {
+        let mut m = b"manifest".to_vec();
+        m.extend_from_slice(&self.session_id);
+        for x in [self.threshold, self.vec_len, self.members.len()] {
+            m.extend_from_slice(&(x as u64).to_le_bytes());
+        }
+        for (id, pk) in self.members.iter() {
+            m.extend_from_slice(&(*id as u64).to_le_bytes());
+            m.extend_from_slice(pk);
+        }
+        m
+    }
+}
+
+impl Manifest {
+    // Checks that `manifest` was signed by the coordinator for the session
+    // we expect, and that we are one of its members under our own key.
+    pub fn verify(
+        manifest: Signed<Manifest>,
+        coordinator_pk: &SignPublicKey,
+        session_id: &SessionId,
+        id: usize,
+        sign_pk: &SignPublicKey,
+    ) -> Result<Manifest, AggregationError> {
+        manifest.verify(coordinator_pk)?;
+        let manifest = manifest.into_msg();
+        if manifest.session_id != *session_id {
+            return Err(ErrorKind::SessionMismatch.into())
+        }
+        if manifest.members.get(&id) != Some(sign_pk) {
+            return Err(AggregationError::new(ErrorKind::UnknownUser).for_user(id))
+        }
+        Ok(manifest)
+    }
+}
+
+// Collects the registrations of the users on behalf of the coordinator.
+pub struct Registrar {
+    session_id: SessionId,
+    threshold: usize,
+    vec_len: usize,
+    members: BTreeMap<usize, SignPublicKey>,
+}
+
+impl Registrar {
+    pub fn new(session_id: SessionId, threshold: usize, vec_len: usize) -> Self {
+        Registrar { session_id, threshold, vec_len, members: BTreeMap::new() }
+    }
+
+    pub fn recv(&mut self, registration: Signed<Registration>) -> Result<(), AggregationError> {
+        let Registration { session_id, id, sign_pk } = registration.msg().clone();
+        registration.verify(&sign_pk).map_err(|e| e.for_user(id))?;
+        if session_id != self.session_id {
+            return Err(AggregationError::new(ErrorKind::SessionMismatch).for_user(id))
+        }
+        match self.members.get(&id) {
+            Some(pk) if *pk != sign_pk => Err(AggregationError::new(ErrorKind::Equivocation).for_user(id)),
+            _ => {
+                self.members.insert(id, sign_pk);
+                Ok(())
+            }
+        }
+    }
+
+    // Closes the registration, and signs the manifest of the session.
+    pub fn publish(self, coordinator_sk: &SignSecretKey) -> Result<Signed<Manifest>, AggregationError> {
+        if self.members.len() < self.threshold {
+            let kind = ErrorKind::BelowThreshold { threshold: self.threshold, received: self.members.len() };
+            return Err(kind.into())
+        }
+        shamir::check_parameters(self.threshold, self.members.keys())?;
+        let manifest = Manifest {
+            session_id: self.session_id,
+            threshold: self.threshold,
+            vec_len: self.vec_len,
+            members: self.members,
+        };
+        Ok(Signed::wrap(manifest, coordinator_sk))
+    }
+}
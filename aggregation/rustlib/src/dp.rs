@@ -0,0 +1,185 @@
+use rand::{Rng, RngCore};
+use rand_distr::{Distribution, Geometric, Poisson};
+use serde::{Serialize, Deserialize};
+
+use crate::error::*;
+
+// Distributed differential privacy: every user adds a small amount of
+// integer noise to its input before masking it, so that the aggregate the
+// server unmasks carries enough noise without anyone trusting the server.
+//
+// The noise of each user is scaled so that the aggregate gets the target
+// variance when `expected_survivors` inputs are summed. Fewer survivors
+// means less noise, which the `Accountant` takes into account.
+//
+// See *The Distributed Discrete Gaussian Mechanism for Federated Learning
+// with Secure Aggregation*, Kairouz et. al. https://arxiv.org/abs/2102.06387
+// and *The Skellam Mechanism for Differentially Private Federated Learning*,
+// Agarwal et. al. https://arxiv.org/abs/2110.04995
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Mechanism {
+    DiscreteGaussian,
+    Skellam,
+}
+
+// Checked by `new`, including when deserialized, so that the noise of each
+// user can always be sampled.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "UncheckedNoiseConfig")]
+pub struct NoiseConfig {
+    mechanism: Mechanism,
+    // Variance of the noise in the aggregate, in the integer domain.
+    variance: f64,
+    expected_survivors: usize,
+}
+
+#[derive(Deserialize)]
+struct UncheckedNoiseConfig {
+    mechanism: Mechanism,
+    variance: f64,
+    expected_survivors: usize,
+}
+
+impl TryFrom<UncheckedNoiseConfig> for NoiseConfig {
+    type Error = AggregationError;
+
+    fn try_from(c: UncheckedNoiseConfig) -> Result<Self, AggregationError> {
+        NoiseConfig::new(c.mechanism, c.variance, c.expected_survivors)
+    }
+}
+
+impl NoiseConfig {
+    pub fn new(mechanism: Mechanism, variance: f64, expected_survivors: usize) -> Result<Self, AggregationError> {
+        let noise = NoiseConfig { mechanism, variance, expected_survivors };
+        // The variance of each user must not vanish either, and the sum of
+        // discrete Gaussians is only close to a discrete Gaussian when each
+        // has a variance of at least 1, see `Accountant::rdp`.
+        let min_user_variance = match mechanism {
+            Mechanism::Skellam => 0.,
+            Mechanism::DiscreteGaussian => 1.,
+        };
+        if variance.is_finite() && expected_survivors > 0 && noise.user_variance() / 2. > 0.
+            && noise.user_variance() >= min_user_variance {
+            Ok(noise)
+        } else {
+            Err(ErrorKind::InvalidParameters.into())
+        }
+    }
+
+    pub fn mechanism(&self) -> Mechanism {
+        self.mechanism
+    }
+
+    pub fn variance(&self) -> f64 {
+        self.variance
+    }
+
+    pub fn expected_survivors(&self) -> usize {
+        self.expected_survivors
+    }
+
+    // Variance of the noise added by each user.
+    pub fn user_variance(&self) -> f64 {
+        self.variance / self.expected_survivors as f64
+    }
+
+    // Variance of the noise in an aggregate of `survivors` inputs.
+    pub fn aggregate_variance(&self, survivors: usize) -> f64 {
+        self.user_variance() * survivors as f64
+    }
+
+    pub fn sample<G: RngCore>(&self, len: usize, rng: &mut G) -> Vec<i64> {
+        match self.mechanism {
+            Mechanism::Skellam => {
+                // The difference of two Poisson(mu) has variance 2 mu.
+                let poisson = Poisson::new(self.user_variance() / 2.).expect("checked by NoiseConfig::new");
+                (0..len).map(|_| poisson.sample(rng) as i64 - poisson.sample(rng) as i64).collect()
+            },
+            Mechanism::DiscreteGaussian =>
+                (0..len).map(|_| sample_discrete_gaussian(self.user_variance(), rng)).collect(),
+        }
+    }
+}
+
+// Rejection sampling from a discrete Laplace distribution, following
+// *The Discrete Gaussian for Differential Privacy*, Canonne et. al.
+// https://arxiv.org/abs/2004.00010 (Algorithm 3).
+fn sample_discrete_gaussian<G: RngCore>(variance: f64, rng: &mut G) -> i64 {
+    let t = variance.sqrt().floor() + 1.;
+    // As the variance is positive, t >= 1 and 0 < p < 1.
+    let geometric = Geometric::new(1. - (-1. / t).exp()).expect("probability in (0, 1)");
+    loop {
+        let y = geometric.sample(rng) as i64 - geometric.sample(rng) as i64;
+        let c = (y.unsigned_abs() as f64 - variance / t).powi(2) / (2. * variance);
+        if rng.gen_bool((-c).exp()) {
+            break y
+        }
+    }
+}
+
+// Reports the privacy of an aggregate, given how many inputs it contains.
+// Each epsilon is that of a single aggregate: the iterations of a session,
+// see `Server::with_iterations`, each release an aggregate of their own,
+// and the epsilons of these are not composed here.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Accountant {
+    noise: NoiseConfig,
+    l1_sensitivity: f64,
+    l2_sensitivity: f64,
+    threshold: usize,
+}
+
+impl Accountant {
+    // `l2_sensitivity` bounds the norm of the encoded input of a single user,
+    // and `dim` is the length of that input.
+    pub fn new(noise: NoiseConfig, l2_sensitivity: f64, dim: usize, threshold: usize) -> Result<Self, AggregationError> {
+        if !(l2_sensitivity.is_finite() && l2_sensitivity > 0.) || threshold == 0 {
+            return Err(ErrorKind::InvalidParameters.into())
+        }
+        // For integer vectors, |x|_1 <= min(|x|_2^2, sqrt(dim) |x|_2).
+        let l1_sensitivity = f64::min(l2_sensitivity.powi(2), (dim as f64).sqrt() * l2_sensitivity);
+        Ok(Accountant { noise, l1_sensitivity, l2_sensitivity, threshold })
+    }
+
+    // Rényi DP of order `alpha` of an aggregate of `survivors` inputs.
+    fn rdp(&self, alpha: f64, survivors: usize) -> f64 {
+        let variance = self.noise.aggregate_variance(survivors);
+        let gaussian = alpha * self.l2_sensitivity.powi(2) / (2. * variance);
+        match self.noise.mechanism {
+            // Agarwal et. al., Theorem 3.5, with mu = variance / 2.
+            Mechanism::Skellam => {
+                let mu = variance / 2.;
+                gaussian + f64::min(
+                    ((2. * alpha - 1.) * self.l2_sensitivity.powi(2) + 6. * self.l1_sensitivity) / (16. * mu.powi(2)),
+                    3. * self.l1_sensitivity / (4. * mu))
+            },
+            // The sum of discrete Gaussians is very close to a discrete
+            // Gaussian (Kairouz et. al., Theorem 1) as the noise of each
+            // user has a variance of at least 1; we neglect the gap.
+            Mechanism::DiscreteGaussian => gaussian,
+        }
+    }
+
+    // The epsilon of an aggregate of `survivors` inputs, for the given delta.
+    pub fn epsilon(&self, survivors: usize, delta: f64) -> Result<f64, AggregationError> {
+        if survivors < self.threshold {
+            return Err(ErrorKind::BelowThreshold { threshold: self.threshold, received: survivors }.into())
+        }
+        if !(delta > 0. && delta < 1.) {
+            return Err(ErrorKind::InvalidParameters.into())
+        }
+        // Conversion from RDP, minimized over a grid of orders.
+        let eps = (1..=1000)
+            .map(|i| 1. + i as f64 / 10.)
+            .map(|alpha| self.rdp(alpha, survivors) + (1. / delta).ln() / (alpha - 1.))
+            .fold(f64::INFINITY, f64::min);
+        Ok(eps)
+    }
+
+    // The epsilon guaranteed whatever the dropouts, as the server never
+    // unmasks an aggregate of fewer than `threshold` inputs.
+    pub fn worst_case_epsilon(&self, delta: f64) -> Result<f64, AggregationError> {
+        self.epsilon(self.threshold, delta)
+    }
+}
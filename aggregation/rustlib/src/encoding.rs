@@ -0,0 +1,161 @@
+use std::num::Wrapping;
+use std::marker::PhantomData;
+
+use crate::ring::Ring;
+use crate::dp::NoiseConfig;
+use crate::error::*;
+
+// Fixed-point encoding of float vectors into the ring the protocol
+// aggregates over.
+//
+// Each coordinate is clipped to [-clip, clip], multiplied by `scale` and
+// rounded. Sums are computed modulo q = `R::modulus()` and read back as
+// signed integers, so the aggregate is exact as long as the sum of the
+// encoded values stays within [-(q - 1) / 2, (q - 1) / 2].
+//
+// In weighted mode, each input is multiplied by the weight of its user, and
+// with distributed DP each user adds noise to it, see `dp`: both count
+// towards the bound, so they are declared with `with_max_weight` and
+// `with_noise`. Users and servers are built with an encoding, and refuse
+// sessions with more participants than it can sum.
+
+// Probability that the noise of one coordinate of the aggregate exceeds the
+// bound we account for.
+const NOISE_TAIL: f64 = 1. / (1u64 << 40) as f64;
+
+pub trait Float: Copy {
+    fn to_f64(self) -> f64;
+    fn from_f64(x: f64) -> Self;
+}
+
+impl Float for f32 {
+    fn to_f64(self) -> f64 { self as f64 }
+    fn from_f64(x: f64) -> Self { x as f32 }
+}
+
+impl Float for f64 {
+    fn to_f64(self) -> f64 { self }
+    fn from_f64(x: f64) -> Self { x }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FixedPointEncoding<R: Ring = Wrapping<i64>> {
+    scale: f64,
+    clip: f64,
+    max_weight: u64,
+    noise: Option<NoiseConfig>,
+    ring: PhantomData<R>,
+}
+
+impl<R: Ring> FixedPointEncoding<R> {
+    pub fn new(scale: f64, clip: f64) -> Result<Self, AggregationError> {
+        let valid = scale.is_finite() && scale > 0.
+            && clip.is_finite() && clip > 0.;
+        let encoding = FixedPointEncoding { scale, clip, max_weight: 1, noise: None, ring: PhantomData };
+        if valid && encoding.max_encoded() >= 1 && encoding.max_encoded() <= encoding.max_sum() {
+            Ok(encoding)
+        } else {
+            Err(ErrorKind::InvalidParameters.into())
+        }
+    }
+
+    pub fn scale(&self) -> f64 {
+        self.scale
+    }
+
+    pub fn clip(&self) -> f64 {
+        self.clip
+    }
+
+    // Inputs are multiplied by weights of at most `max_weight`, see
+    // `User::new_weighted`.
+    pub fn with_max_weight(mut self, max_weight: u64) -> Result<Self, AggregationError> {
+        if max_weight == 0 {
+            return Err(ErrorKind::InvalidParameters.into())
+        }
+        self.max_weight = max_weight;
+        Ok(self)
+    }
+
+    // Inputs carry noise from `noise`, which the users add to them.
+    pub fn with_noise(mut self, noise: NoiseConfig) -> Self {
+        self.noise = Some(noise);
+        self
+    }
+
+    pub fn max_weight(&self) -> u64 {
+        self.max_weight
+    }
+
+    pub fn noise(&self) -> Option<&NoiseConfig> {
+        self.noise.as_ref()
+    }
+
+    fn max_encoded(&self) -> u128 {
+        (self.clip * self.scale).round() as u128
+    }
+
+    fn max_sum(&self) -> u128 {
+        (R::modulus() - 1) / 2
+    }
+
+    // Bound on the noise of one coordinate of the sum of `contributors`
+    // inputs, exceeded with probability at most `NOISE_TAIL`. Both mechanisms
+    // give noise with Bernstein tails,
+    //   P(|X| >= t) <= 2 exp(-t^2 / (2 (variance + t / 3))),
+    // the Skellam one as a sum of jumps of 1, and the discrete Gaussian one as
+    // it is subgaussian with the same variance (Canonne et. al.).
+    fn noise_bound(&self, contributors: usize) -> u128 {
+        let Some(noise) = &self.noise else { return 0 };
+        let l = (2. / NOISE_TAIL).ln();
+        let variance = noise.aggregate_variance(contributors);
+        (l / 3. + (l * l / 9. + 2. * l * variance).sqrt()).ceil() as u128
+    }
+
+    // Number of contributions which can be summed without overflowing the
+    // ring, given their weights and noise.
+    pub fn max_contributors(&self) -> usize {
+        let per_user = self.max_encoded().saturating_mul(self.max_weight as u128);
+        let fits = |n: usize| {
+            per_user.saturating_mul(n as u128).saturating_add(self.noise_bound(n)) <= self.max_sum()
+        };
+        // Both terms grow with the number of contributors.
+        let (mut lo, mut hi) = (0, usize::try_from(self.max_sum() / per_user).unwrap_or(usize::MAX));
+        while lo < hi {
+            let mid = hi - (hi - lo) / 2;
+            if fits(mid) { lo = mid } else { hi = mid - 1 }
+        }
+        lo
+    }
+
+    pub fn check_contributors(&self, contributors: usize) -> Result<(), AggregationError> {
+        let max = self.max_contributors();
+        if contributors > max {
+            Err(ErrorKind::TooManyContributors { max, received: contributors }.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn encode<T: Float>(&self, v: &[T]) -> Result<Vec<R>, AggregationError> {
+        v.iter().map(|x| {
+            let x = x.to_f64();
+            if x.is_nan() {
+                return Err(ErrorKind::InvalidParameters.into())
+            }
+            Ok(R::from_i64((x.clamp(-self.clip, self.clip) * self.scale).round() as i64))
+        }).collect()
+    }
+
+    // Decodes the sum of `contributors` encoded vectors.
+    pub fn decode<T: Float>(&self, v: &[R], contributors: usize) -> Result<Vec<T>, AggregationError> {
+        self.check_contributors(contributors)?;
+
+        Ok(v.iter().map(|x| {
+            // Map the representative in [0, q) back to a signed integer.
+            let x = x.to_u64() as i128;
+            let x = if x as u128 > self.max_sum() { x - R::modulus() as i128 } else { x };
+            T::from_f64(x as f64 / self.scale)
+        }).collect())
+    }
+}
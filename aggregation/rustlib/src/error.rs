@@ -0,0 +1,107 @@
+use std::fmt;
+
+use serde::{Serialize, Deserialize};
+
+// Every failure of the protocol is reported as an `AggregationError`,
+// which records in which round it happened, which user is to blame
+// (if any), and why.
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorKind {
+    BelowThreshold { threshold: usize, received: usize },
+    BadSignature,
+    EncryptionFailure,
+    DecryptionFailure,
+    ShareReconstruction,
+    MalformedMessage,
+    UnexpectedRound,
+    LengthMismatch { expected: usize, received: usize },
+    InvalidParameters,
+    TooManyContributors { max: usize, received: usize },
+    UnknownUser,
+    UnexpectedSender,
+    SessionMismatch,
+    StaleSnapshot { latest: u64, received: u64 },
+    UnsupportedVersion { supported: u32, received: u32 },
+    BadMagic,
+    Equivocation,
+    Randomness,
+    Serialization,
+    // A failure recorded by an older version, which did not tell its cause.
+    Unknown,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::BelowThreshold { threshold, received } =>
+                write!(f, "below threshold ({} received, {} required)", received, threshold),
+            ErrorKind::BadSignature => write!(f, "bad signature"),
+            ErrorKind::EncryptionFailure => write!(f, "encryption failure"),
+            ErrorKind::DecryptionFailure => write!(f, "decryption failure"),
+            ErrorKind::ShareReconstruction => write!(f, "share reconstruction failure"),
+            ErrorKind::MalformedMessage => write!(f, "malformed message"),
+            ErrorKind::UnexpectedRound => write!(f, "unexpected round"),
+            ErrorKind::LengthMismatch { expected, received } =>
+                write!(f, "length mismatch ({} expected, {} received)", expected, received),
+            ErrorKind::InvalidParameters => write!(f, "unsupported parameters"),
+            ErrorKind::TooManyContributors { max, received } =>
+                write!(f, "too many contributors ({} received, at most {} supported)", received, max),
+            ErrorKind::UnknownUser => write!(f, "unknown user"),
+            ErrorKind::UnexpectedSender => write!(f, "sender not expected in this round"),
+            ErrorKind::SessionMismatch => write!(f, "message from another session"),
+            ErrorKind::StaleSnapshot { latest, received } =>
+                write!(f, "stale snapshot (counter {} received, at least {} expected)", received, latest),
+            ErrorKind::UnsupportedVersion { supported, received } =>
+                write!(f, "unsupported format version ({} received, {} supported)", received, supported),
+            ErrorKind::BadMagic => write!(f, "not a message of the protocol"),
+            ErrorKind::Equivocation => write!(f, "conflicting messages"),
+            ErrorKind::Randomness => write!(f, "could not gather randomness"),
+            ErrorKind::Serialization => write!(f, "serialization failure"),
+            ErrorKind::Unknown => write!(f, "unknown failure"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AggregationError {
+    pub round: Option<usize>,
+    pub user: Option<usize>,
+    pub kind: ErrorKind,
+}
+
+impl AggregationError {
+    pub fn new(kind: ErrorKind) -> Self {
+        AggregationError { round: None, user: None, kind }
+    }
+
+    pub fn at_round(mut self, round: Option<usize>) -> Self {
+        self.round = round;
+        self
+    }
+
+    pub fn for_user(mut self, user: usize) -> Self {
+        self.user = Some(user);
+        self
+    }
+}
+
+impl From<ErrorKind> for AggregationError {
+    fn from(kind: ErrorKind) -> Self {
+        AggregationError::new(kind)
+    }
+}
+
+impl fmt::Display for AggregationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(round) = self.round {
+            write!(f, "round {}: ", round)?;
+        }
+        if let Some(user) = self.user {
+            write!(f, "user {}: ", user)?;
+        }
+        write!(f, "{}", self.kind)
+    }
+}
+
+impl std::error::Error for AggregationError {}
@@ -0,0 +1,183 @@
+use std::num::Wrapping;
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Serialize, Deserialize};
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde_json::{json, Value};
+
+use crate::sodium_bindings::{hash, Hash};
+use crate::helpers::add_assign;
+use crate::graph::Neighbourhoods;
+use crate::types::Iteration;
+use crate::error::*;
+
+// Everything this crate persists -- the states of `serialize_state` and the
+// snapshots of `snapshot` -- is written as JSON, in an envelope giving the
+// kind of what it holds and the version of its layout.
+//
+// Compatibility policy:
+// - we always write the current version;
+// - we read every older version, migrating it one version at a time;
+// - we refuse newer versions, rather than guess at their layout.
+// Any change to the layout of `UserState`, `ServerState`, `UserData` or of
+// the snapshots bumps `FORMAT_VERSION`, adds a step to `migrate`, and adds
+// golden files to `tests/golden`.
+//
+// Versions:
+// 0. The bare states written by `serialize_state` before the envelope, see
+//    `user_state_v0` and `server_state_v0`. There were no snapshots.
+// 1. The current version.
+
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Kind {
+    UserState,
+    ServerState,
+    UserSnapshot,
+    ServerSnapshot,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    version: u32,
+    kind: Kind,
+    body: Value,
+}
+
+pub fn wrap<T: Serialize>(kind: Kind, body: &T) -> Result<String, AggregationError> {
+    let body = serde_json::to_value(body).map_err(|_| ErrorKind::Serialization)?;
+    serde_json::to_string(&Envelope { version: FORMAT_VERSION, kind, body }).map_err(|_| ErrorKind::Serialization.into())
+}
+
+pub fn unwrap<T: DeserializeOwned>(kind: Kind, bytes: &[u8]) -> Result<T, AggregationError> {
+    let value: Value = serde_json::from_slice(bytes).map_err(|_| ErrorKind::Serialization)?;
+    let envelope = match value {
+        Value::Object(ref m) if m.contains_key("version") =>
+            serde_json::from_value(value).map_err(|_| ErrorKind::Serialization)?,
+        body => Envelope { version: 0, kind, body },
+    };
+    if envelope.version > FORMAT_VERSION {
+        return Err(ErrorKind::UnsupportedVersion { supported: FORMAT_VERSION, received: envelope.version }.into())
+    }
+    if envelope.kind != kind {
+        return Err(ErrorKind::MalformedMessage.into())
+    }
+    let body = migrate(kind, envelope.version, envelope.body)?;
+    serde_json::from_value(body).map_err(|_| ErrorKind::Serialization.into())
+}
+
+// Brings `body` from `version` up to `FORMAT_VERSION`.
+fn migrate(kind: Kind, mut version: u32, mut body: Value) -> Result<Value, AggregationError> {
+    while version < FORMAT_VERSION {
+        body = match (version, kind) {
+            (0, Kind::UserState) => user_state_v0(body)?,
+            (0, Kind::ServerState) => server_state_v0(body)?,
+            _ => return Err(ErrorKind::UnsupportedVersion { supported: FORMAT_VERSION, received: version }.into()),
+        };
+        version += 1;
+    }
+    Ok(body)
+}
+
+// In version 0, sessions had a single iteration over the complete graph, a
+// failure did not record its cause, and the server kept every masked input
+// rather than their sum. The values which did not change are kept as they
+// are.
+//
+// Shares were then over GF(2^8), and are not read anymore: a session can
+// only go on after Round 1, which shares the seeds, if it was migrated
+// before, and otherwise fails when unmasking. The server state of Round 4,
+// which holds such shares in the clear, cannot be migrated.
+
+#[derive(Deserialize)]
+enum UserStateV0 {
+    Round0,
+    Round1(Value),
+    Round2(Value, Value, Value),
+    Round3(Value, Value, Value, BTreeMap<usize, Value>),
+    Round4(Value, Value, Value, BTreeMap<usize, Value>, Value),
+    Done,
+    Failed,
+}
+
+#[derive(Deserialize)]
+struct CollectorV0<T> {
+    threshold: usize,
+    map: BTreeMap<usize, T>,
+}
+
+#[derive(Deserialize)]
+enum ServerStateV0 {
+    Round0(CollectorV0<Value>),
+    Round1(CollectorV0<Value>, Value),
+    Round2(CollectorV0<Vec<Wrapping<i64>>>, Value, BTreeSet<usize>),
+    Round3(CollectorV0<Value>, Value, BTreeSet<usize>, Vec<Vec<Wrapping<i64>>>, BTreeSet<usize>),
+    Round4(IgnoredAny),
+    Done,
+    Failed,
+}
+
+fn first_iteration(participants: BTreeSet<usize>) -> Iteration {
+    Iteration { index: 0, participants }
+}
+
+fn failed_v0() -> Value {
+    json!({ "Failed": AggregationError::new(ErrorKind::Unknown) })
+}
+
+// A single seed, and the users who sent us shares in the first iteration.
+fn user_state_v0(body: Value) -> Result<Value, AggregationError> {
+    let state: UserStateV0 = serde_json::from_value(body).map_err(|_| ErrorKind::Serialization)?;
+    let participants = |crypted: &BTreeMap<usize, Value>| first_iteration(crypted.keys().cloned().collect());
+    Ok(match state {
+        UserStateV0::Round0 => json!("Round0"),
+        UserStateV0::Round1(own) => json!({ "Round1": own }),
+        UserStateV0::Round2(own, others, seed) => json!({ "Round2": [own, others, [seed]] }),
+        UserStateV0::Round3(own, others, seed, crypted) => {
+            let iteration = participants(&crypted);
+            json!({ "Round3": [own, others, [seed], crypted, iteration] })
+        },
+        UserStateV0::Round4(own, others, seed, crypted, alive) => {
+            let iteration = participants(&crypted);
+            json!({ "Round4": [own, others, [seed], crypted, iteration, alive] })
+        },
+        UserStateV0::Done => json!("Done"),
+        UserStateV0::Failed => failed_v0(),
+    })
+}
+
+// Nobody had equivocated, as a message sent twice was silently replaced.
+// The masked inputs are folded into an `Accumulator` or their sum, and the
+// users who sent shares make the first iteration. A Round 2 without any
+// masked input does not tell their length, and is refused by
+// `Server::recover_state`.
+fn server_state_v0(body: Value) -> Result<Value, AggregationError> {
+    let state: ServerStateV0 = serde_json::from_value(body).map_err(|_| ErrorKind::Serialization)?;
+    let collector = |c: CollectorV0<Value>| json!({ "threshold": c.threshold, "map": c.map, "equivocators": [] });
+    let sum = |vecs: &mut dyn Iterator<Item=&Vec<Wrapping<i64>>>| {
+        vecs.fold(None, |acc: Option<Vec<Wrapping<i64>>>, v| match acc {
+            Some(mut acc) => { add_assign(&mut acc, v); Some(acc) },
+            None => Some(v.clone()),
+        }).unwrap_or_default()
+    };
+    let state = match state {
+        ServerStateV0::Round0(c) => json!({ "Round0": collector(c) }),
+        ServerStateV0::Round1(c, rand_pks) => json!({ "Round1": [collector(c), rand_pks, Neighbourhoods::Complete] }),
+        ServerStateV0::Round2(c, rand_pks, sharing) => {
+            let digests = c.map.iter()
+                .map(|(u, v)| Ok((*u, hash(&bincode::serialize(v).map_err(|_| ErrorKind::Serialization)?))))
+                .collect::<Result<BTreeMap<usize, Hash>, AggregationError>>()?;
+            let acc = json!({ "threshold": c.threshold, "sum": sum(&mut c.map.values()), "digests": digests });
+            json!({ "Round2": [acc, rand_pks, Neighbourhoods::Complete, first_iteration(sharing)] })
+        },
+        ServerStateV0::Round3(c, rand_pks, sharing, vecs, alive) =>
+            json!({ "Round3": [collector(c), rand_pks, Neighbourhoods::Complete, first_iteration(sharing), sum(&mut vecs.iter()), alive] }),
+        ServerStateV0::Round4(_) =>
+            return Err(ErrorKind::UnsupportedVersion { supported: FORMAT_VERSION, received: 0 }.into()),
+        ServerStateV0::Done => json!("Done"),
+        ServerStateV0::Failed => failed_v0(),
+    };
+    Ok(json!({ "equivocators": [], "state": state }))
+}
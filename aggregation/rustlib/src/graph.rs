@@ -0,0 +1,67 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use rand::seq::SliceRandom;
+use rand::rngs::OsRng;
+use serde::{Serialize, Deserialize};
+
+use crate::error::*;
+
+// SecAgg+ -- See *Secure Single-Server Aggregation with (Poly)Logarithmic
+// Overhead*, Bell et. al. https://eprint.iacr.org/2020/704
+//
+// Instead of sharing its keys with and masking its input against every
+// other user, each user only does so with its neighbours in a random
+// k-regular graph picked by the server in Round 0, k being typically
+// O(log n). Secrets are then reconstructed within each neighbourhood, with
+// a threshold relative to its size rather than to the number of users.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SparseGraph {
+    // Number of neighbours of each user, which must be even.
+    pub degree: usize,
+    // Number of neighbours needed to recover the secrets of a user.
+    pub threshold: usize,
+}
+
+impl SparseGraph {
+    pub fn new(degree: usize, threshold: usize) -> Result<Self, AggregationError> {
+        // A user is part of its own neighbourhood.
+        if degree == 0 || degree % 2 != 0 || threshold == 0 || threshold > degree + 1 {
+            Err(ErrorKind::InvalidParameters.into())
+        } else {
+            Ok(SparseGraph { degree, threshold })
+        }
+    }
+}
+
+// The neighbourhood of every user, which includes the user itself.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Neighbourhoods {
+    Complete,
+    Sparse(BTreeMap<usize, BTreeSet<usize>>),
+}
+
+impl Neighbourhoods {
+    // A Harary graph over a random permutation of the users: on a cycle,
+    // every user is linked to the `degree / 2` closest users on each side.
+    pub fn random(ids: &BTreeSet<usize>, graph: &SparseGraph) -> Self {
+        let n = ids.len();
+        if graph.degree + 1 >= n {
+            return Neighbourhoods::Complete
+        }
+        let mut cycle = ids.iter().cloned().collect::<Vec<usize>>();
+        cycle.shuffle(&mut OsRng);
+        let half = graph.degree / 2;
+        Neighbourhoods::Sparse((0..n).map(|i| {
+            let neighbours = (n - half..=n + half).map(|j| cycle[(i + j) % n]).collect();
+            (cycle[i], neighbours)
+        }).collect())
+    }
+
+    pub fn are_neighbours(&self, u: usize, v: usize) -> bool {
+        match self {
+            Neighbourhoods::Complete => true,
+            Neighbourhoods::Sparse(m) => m.get(&u).is_some_and(|n| n.contains(&v)),
+        }
+    }
+}
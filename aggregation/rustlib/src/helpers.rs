@@ -1,18 +1,35 @@
 
-use std::num::Wrapping;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 
-use rand::{Rng, SeedableRng};
+use rand::SeedableRng;
 use rand_chacha::ChaCha8Rng;
 use serde::{Serialize, Deserialize};
 use serde_big_array::big_array;
+use zeroize::{Zeroize, Zeroizing};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 
 serde_big_array::big_array! { BigArray; }
 
 use crate::sodium_bindings::*;
+use crate::secret::Secret;
+use crate::ring::Ring;
+use crate::shamir::Share;
+use crate::error::*;
 
 pub type KAPublicKey = [u8; 32];
-pub type KASecretKey = [u8; 32];
+pub type KASecretKey = Secret<32>;
+// The self-mask seed of a user, and shared secrets.
+pub type Seed = Secret<32>;
+
+// Diffie-Hellman through the types of `x25519_dalek`, which are wiped when
+// dropped, rather than `x25519_dalek::x25519`, which leaves copies around.
+pub fn key_agreement(sk: &KASecretKey, pk: &KAPublicKey) -> Seed {
+    let bytes = Zeroizing::new(*sk.expose());
+    let sk = x25519_dalek::StaticSecret::from(*bytes);
+    let shared = sk.diffie_hellman(&x25519_dalek::PublicKey::from(*pk));
+    Secret::copy_from(shared.as_bytes())
+}
 
 pub trait Signable {
     fn as_message(&self) -> Vec<u8>;
@@ -34,7 +51,7 @@ impl<T: Signable> Signed<T> {
         }
     }
 
-    pub fn verify(&self, pk: &SignPublicKey) -> Result<(), ()> {
+    pub fn verify(&self, pk: &SignPublicKey) -> Result<(), AggregationError> {
         verify_signature(&self.msg.as_message(), &self.sig, pk)
     }
 
@@ -45,30 +62,42 @@ impl<T: Signable> Signed<T> {
     pub fn into_msg(self) -> T {
         self.msg
     }
+
+    pub fn sig(&self) -> &Signature {
+        &self.sig
+    }
+
+    // A message as received, which is yet to be checked with `verify`.
+    pub fn from_parts(msg: T, sig: Signature) -> Signed<T> {
+        Signed { msg, sig }
+    }
 }
 
 impl Signable for KAPublicKey {
     fn as_message(&self) -> Vec<u8> { self.to_vec() }
 }
 
+// The shares sent by `u` to `v`, with one seed share per iteration;
+// all are evaluated at the point of `v`.
 #[derive(Serialize, Deserialize)]
 pub struct MaskGenShares {
     pub u: usize,
     pub v: usize,
-    pub rand_sk_share: Vec<u8>,
-    pub seed_share: Vec<u8>,
+    pub rand_sk_share: Share,
+    pub seed_shares: Vec<Share>,
 }
 
 impl MaskGenShares {
-    pub fn new(u: usize, v: usize, rand_sk_share: Vec<u8>, seed_share: Vec<u8>) -> Self {
-        MaskGenShares { u, v, rand_sk_share, seed_share }
+    pub fn new(u: usize, v: usize, rand_sk_share: Share, seed_shares: Vec<Share>) -> Self {
+        MaskGenShares { u, v, rand_sk_share, seed_shares }
     }
 }
 
+// A share revealed in Round 4, evaluated at the point of the user revealing it.
 #[derive(Serialize, Deserialize)]
 pub enum RevealedShare {
-    RandSk(Vec<u8>),
-    Seed(Vec<u8>),
+    RandSk(Share),
+    Seed(Share),
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -78,31 +107,89 @@ pub struct CryptoMsg {
 }
 
 impl CryptoMsg {
-    pub fn new(m: &[u8], k: Key) -> Result<Self, ()> {
+    pub fn new(m: &[u8], k: &Seed) -> Result<Self, AggregationError> {
         let nonce = gen_nonce();
-        Ok(CryptoMsg { nonce, c: crypto_secret_wrap(m, nonce, k)? })
+        Ok(CryptoMsg { nonce, c: crypto_secret_wrap(m, nonce, k.expose())? })
     }
 
-    pub fn unwrap(&self, k: Key) -> Result<Vec<u8>, ()> {
-        crypto_secret_unwrap(&self.c, self.nonce, k)
+    pub fn unwrap(&self, k: &Seed) -> Result<Zeroizing<Vec<u8>>, AggregationError> {
+        crypto_secret_unwrap(&self.c, self.nonce, k.expose()).map(Zeroizing::new)
     }
 }
 
-pub fn vector_from_seed(seed: [u8; 32], length: usize) -> Vec<Wrapping<i64>> {
-    let mut noise = vec![Wrapping(0); length];
+pub fn vector_from_seed<R: Ring>(seed: [u8; 32], length: usize) -> Vec<R> {
     let mut rng = ChaCha8Rng::from_seed(seed);
-    rng.fill(noise.as_mut_slice());
-    noise
+    (0..length).map(|_| R::sample(&mut rng)).collect()
+}
+
+// The seed of a pairwise mask in a given iteration of a session, so that
+// every iteration gets fresh masks out of the same key agreement.
+pub fn iteration_seed(seed: &Seed, iteration: usize) -> [u8; 32] {
+    let mut m = Zeroizing::new(seed.expose().to_vec());
+    m.extend((iteration as u64).to_le_bytes());
+    hash(&m)
+}
+
+// Wipes the seeds of masks once applied.
+pub fn wipe_masks(masks: &mut [(Sign, [u8; 32])]) {
+    masks.iter_mut().for_each(|(_, seed)| seed.zeroize());
+}
+
+// The kernels below update an accumulator in place, so that summing n
+// vectors costs no allocation; the loops are simple enough for the
+// compiler to vectorise them.
+
+pub fn add_assign<R: Ring>(acc: &mut [R], v: &[R]) {
+    Iterator::zip(acc.iter_mut(), v).for_each(|(a, b)| *a = a.add(*b));
+}
+
+pub fn sub_assign<R: Ring>(acc: &mut [R], v: &[R]) {
+    Iterator::zip(acc.iter_mut(), v).for_each(|(a, b)| *a = a.sub(*b));
+}
+
+// Adds the mask `vector_from_seed(seed, acc.len())` to `acc` as it is
+// generated, without ever materialising it.
+pub fn add_mask<R: Ring>(acc: &mut [R], seed: [u8; 32]) {
+    let mut rng = ChaCha8Rng::from_seed(seed);
+    acc.iter_mut().for_each(|a| *a = a.add(R::sample(&mut rng)));
+}
+
+pub fn sub_mask<R: Ring>(acc: &mut [R], seed: [u8; 32]) {
+    let mut rng = ChaCha8Rng::from_seed(seed);
+    acc.iter_mut().for_each(|a| *a = a.sub(R::sample(&mut rng)));
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sign {
+    Plus,
+    Minus,
+}
+
+fn apply_mask<R: Ring>(acc: &mut [R], (sign, seed): (Sign, [u8; 32])) {
+    match sign {
+        Sign::Plus => add_mask(acc, seed),
+        Sign::Minus => sub_mask(acc, seed),
+    }
 }
 
-pub fn sum_components<I>(v: I, n: usize) -> Vec<Wrapping<i64>>
-    where I: Iterator<Item=Vec<Wrapping<i64>>>
-{
-    v.fold(vec![Wrapping(0); n], |acc, v| { Iterator::zip(acc.into_iter(), v.into_iter()).map(|(a, b)| a + b).collect() })
+// Adds or subtracts all the given masks to `acc`.
+#[cfg(not(feature = "parallel"))]
+pub fn apply_masks<R: Ring>(acc: &mut [R], masks: &[(Sign, [u8; 32])]) {
+    masks.iter().for_each(|m| apply_mask(acc, *m));
 }
 
-pub fn scalar_mul(l: Wrapping<i64>, v: Vec<Wrapping<i64>>) -> Vec<Wrapping<i64>> {
-    v.into_iter().map(|x| l * x).collect()
+// Every worker expands its share of the masks into its own accumulator, and
+// the accumulators are summed at the end. Arithmetic in the ring is exact,
+// so the result does not depend on how the masks were split.
+#[cfg(feature = "parallel")]
+pub fn apply_masks<R: Ring>(acc: &mut [R], masks: &[(Sign, [u8; 32])]) {
+    let len = acc.len();
+    let sum = masks.par_iter()
+        .fold(|| vec![R::zero(); len], |mut a, m| { apply_mask(&mut a, *m); a })
+        .reduce_with(|mut a, b| { add_assign(&mut a, &b); a });
+    if let Some(sum) = sum {
+        add_assign(acc, &sum);
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -117,29 +204,108 @@ impl BundledSignature {
     }
 }
 
+// Collects the messages of a round. A retransmission of the same message
+// is ignored, while a user sending two different messages is rejected, and
+// its messages are excluded from the round.
 #[derive(Serialize, Deserialize)]
 pub struct Collector<T> {
     threshold: usize,
     map: BTreeMap<usize, T>,
+    equivocators: BTreeSet<usize>,
 }
 
-impl<T> Collector<T> {
+impl<T: Serialize> Collector<T> {
     pub fn new(threshold: usize) -> Self {
-        Collector { threshold, map: BTreeMap::new() }
+        Collector { threshold, map: BTreeMap::new(), equivocators: BTreeSet::new() }
     }
 
-    pub fn recv(&mut self, id: usize, x: T) {
-        // Receiving two inputs from the same user isn't a problem
-        // (we just overwrite)
-        self.map.insert(id, x);
+    pub(crate) fn len(&self) -> usize {
+        self.map.len()
     }
 
-    pub fn get(self) -> Result<BTreeMap<usize, T>, ()> {
+    pub fn recv(&mut self, id: usize, x: T) -> Result<(), AggregationError> {
+        let equivocation = AggregationError::new(ErrorKind::Equivocation).for_user(id);
+        if self.equivocators.contains(&id) {
+            return Err(equivocation)
+        }
+        match self.map.get(&id) {
+            None => {
+                self.map.insert(id, x);
+                Ok(())
+            },
+            Some(y) => {
+                let serialize = |z: &T| bincode::serialize(z).map_err(|_| ErrorKind::Serialization);
+                if serialize(y)? == serialize(&x)? {
+                    Ok(())
+                } else {
+                    self.map.remove(&id);
+                    self.equivocators.insert(id);
+                    Err(equivocation)
+                }
+            },
+        }
+    }
+
+    pub fn get(self) -> Result<BTreeMap<usize, T>, AggregationError> {
         if self.map.len() < self.threshold {
-            Err(())
+            Err(ErrorKind::BelowThreshold { threshold: self.threshold, received: self.map.len() }.into())
         } else {
             Ok(self.map)
         }
     }
 }
 
+
+// Folds the masked inputs into their sum as they arrive, so that the server
+// never holds more than one vector. Only a digest of each input is kept, to
+// tell a retransmission, which is ignored, from a conflicting input, which
+// is rejected. The first input of an equivocator cannot be taken back out
+// of the sum, so unlike with a `Collector`, it stays in the round.
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct Accumulator<R: Ring> {
+    threshold: usize,
+    sum: Vec<R>,
+    digests: BTreeMap<usize, Hash>,
+}
+
+impl<R: Ring> Accumulator<R> {
+    pub fn new(threshold: usize, len: usize) -> Self {
+        Accumulator { threshold, sum: vec![R::zero(); len], digests: BTreeMap::new() }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.digests.len()
+    }
+
+    // The length of the inputs.
+    pub fn dim(&self) -> usize {
+        self.sum.len()
+    }
+
+    pub fn recv(&mut self, id: usize, x: Vec<R>) -> Result<(), AggregationError> {
+        if x.len() != self.sum.len() {
+            let kind = ErrorKind::LengthMismatch { expected: self.sum.len(), received: x.len() };
+            return Err(AggregationError::new(kind).for_user(id))
+        }
+        let digest = hash(&bincode::serialize(&x).map_err(|_| ErrorKind::Serialization)?);
+        match self.digests.get(&id) {
+            Some(d) if *d == digest => Ok(()),
+            Some(_) => Err(AggregationError::new(ErrorKind::Equivocation).for_user(id)),
+            None => {
+                add_assign(&mut self.sum, &x);
+                self.digests.insert(id, digest);
+                Ok(())
+            }
+        }
+    }
+
+    // The users whose inputs were summed, and their sum.
+    pub fn get(self) -> Result<(BTreeSet<usize>, Vec<R>), AggregationError> {
+        if self.digests.len() < self.threshold {
+            Err(ErrorKind::BelowThreshold { threshold: self.threshold, received: self.digests.len() }.into())
+        } else {
+            Ok((self.digests.into_keys().collect(), self.sum))
+        }
+    }
+}
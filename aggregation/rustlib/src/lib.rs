@@ -1,7 +1,23 @@
+// The round functions pass the protocol state around as plain tuples,
+// mirroring the variants of `UserState` and `ServerState`.
+#![allow(clippy::type_complexity)]
 
+pub mod error;
+pub mod secret;
 pub mod sodium_bindings;
+pub mod ring;
 pub mod helpers;
+pub mod encoding;
+pub mod dp;
+pub mod shamir;
+pub mod graph;
+pub mod cohort;
+pub mod format;
+pub mod wire;
 pub mod types;
+pub mod cbor;
+pub mod snapshot;
 pub mod user;
+pub mod policy;
 pub mod server;
 
@@ -0,0 +1,88 @@
+use std::time::{Duration, Instant};
+
+use serde::{Serialize, Deserialize};
+
+use crate::error::*;
+
+// Tells the driver of a `Server` when to close the current round, given how
+// many users were expected to answer, how many did, and for how long the
+// round has been open. The clock is injectable, for tests and simulations.
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+// Checked by `new`, including when deserialized.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "UncheckedRoundPolicy")]
+pub struct RoundPolicy {
+    // Below this number of answers, the round fails at the deadline;
+    // the threshold of the server applies too.
+    pub min_participants: usize,
+    // The round closes as soon as this fraction of the users answered.
+    pub target_fraction: f64,
+    // Time after which the round closes with whatever was received.
+    pub deadline: Duration,
+}
+
+#[derive(Deserialize)]
+struct UncheckedRoundPolicy {
+    min_participants: usize,
+    target_fraction: f64,
+    deadline: Duration,
+}
+
+impl TryFrom<UncheckedRoundPolicy> for RoundPolicy {
+    type Error = AggregationError;
+
+    fn try_from(p: UncheckedRoundPolicy) -> Result<Self, AggregationError> {
+        RoundPolicy::new(p.min_participants, p.target_fraction, p.deadline)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RoundStatus {
+    // `Server::round` can be called.
+    Ready,
+    // More answers may still arrive.
+    Wait,
+    // The round cannot succeed anymore.
+    Failed(AggregationError),
+    // The session gave all its aggregates, there is no round left.
+    Done,
+}
+
+impl RoundPolicy {
+    pub fn new(min_participants: usize, target_fraction: f64, deadline: Duration) -> Result<Self, AggregationError> {
+        if target_fraction > 0. && target_fraction <= 1. {
+            Ok(RoundPolicy { min_participants, target_fraction, deadline })
+        } else {
+            Err(ErrorKind::InvalidParameters.into())
+        }
+    }
+
+    pub fn status(&self, threshold: usize, expected: usize, received: usize, elapsed: Duration) -> RoundStatus {
+        let min = usize::max(self.min_participants, threshold);
+        let target = usize::max((self.target_fraction * expected as f64).ceil() as usize, min);
+        if received >= target {
+            RoundStatus::Ready
+        } else if usize::max(received, expected) < min {
+            // Even if every missing user answered, there would not be enough.
+            RoundStatus::Failed(ErrorKind::BelowThreshold { threshold: min, received }.into())
+        } else if elapsed < self.deadline {
+            RoundStatus::Wait
+        } else if received >= min {
+            RoundStatus::Ready
+        } else {
+            RoundStatus::Failed(ErrorKind::BelowThreshold { threshold: min, received }.into())
+        }
+    }
+}
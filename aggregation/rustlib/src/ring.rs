@@ -0,0 +1,189 @@
+use std::fmt::Debug;
+use std::num::Wrapping;
+
+use rand::RngCore;
+use serde::{Serialize, Deserialize, Serializer, Deserializer};
+use serde::de::DeserializeOwned;
+
+// The ring the inputs and masks live in.
+//
+// `Wrapping<i64>` is kept as the default ring of the protocol; smaller rings
+// such as `Wrapping<u16>` or `Zq<Q>` make the masked inputs cheaper to send
+// when the aggregated values are known to fit in fewer bits.
+
+pub trait Ring: Copy + PartialEq + Debug + Send + Sync + Serialize + DeserializeOwned {
+    // Number of bits needed to write down any element.
+    const BITS: u32;
+
+    fn modulus() -> u128;
+
+    fn zero() -> Self;
+    fn add(self, other: Self) -> Self;
+    fn mul(self, other: Self) -> Self;
+    fn neg(self) -> Self;
+
+    fn sub(self, other: Self) -> Self {
+        self.add(other.neg())
+    }
+
+    // Maps an integer to its class modulo the modulus.
+    fn from_i64(x: i64) -> Self;
+
+    // The representative of an element in [0, modulus).
+    fn to_u64(self) -> u64;
+    fn from_u64(x: u64) -> Option<Self>;
+
+    // Samples an element uniformly at random.
+    fn sample<G: RngCore>(rng: &mut G) -> Self;
+}
+
+macro_rules! impl_wrapping_ring {
+    ($t:ty, $bits:expr) => {
+        impl Ring for Wrapping<$t> {
+            const BITS: u32 = $bits;
+
+            fn modulus() -> u128 { 1 << $bits }
+
+            fn zero() -> Self { Wrapping(0) }
+            fn add(self, other: Self) -> Self { self + other }
+            fn mul(self, other: Self) -> Self { self * other }
+            fn neg(self) -> Self { Wrapping(0) - self }
+            fn sub(self, other: Self) -> Self { self - other }
+
+            fn from_i64(x: i64) -> Self { Wrapping(x as $t) }
+
+            fn to_u64(self) -> u64 { self.0 as u64 }
+
+            fn from_u64(x: u64) -> Option<Self> {
+                if (x as u128) < Self::modulus() { Some(Wrapping(x as $t)) } else { None }
+            }
+
+            fn sample<G: RngCore>(rng: &mut G) -> Self { Wrapping(rng.next_u64() as $t) }
+        }
+    }
+}
+
+impl_wrapping_ring!(i64, 64);
+impl_wrapping_ring!(u64, 64);
+impl_wrapping_ring!(u32, 32);
+impl_wrapping_ring!(u16, 16);
+
+// Integers modulo an arbitrary `Q`, with 2 <= Q < 2^63.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(try_from = "u64", into = "u64")]
+pub struct Zq<const Q: u64>(u64);
+
+impl<const Q: u64> Zq<Q> {
+    pub fn new(x: u64) -> Self {
+        Zq(x % Q)
+    }
+
+    pub fn value(self) -> u64 {
+        self.0
+    }
+}
+
+impl<const Q: u64> TryFrom<u64> for Zq<Q> {
+    type Error = String;
+
+    fn try_from(x: u64) -> Result<Self, String> {
+        Self::from_u64(x).ok_or_else(|| format!("{} is not reduced modulo {}", x, Q))
+    }
+}
+
+impl<const Q: u64> From<Zq<Q>> for u64 {
+    fn from(x: Zq<Q>) -> u64 {
+        x.0
+    }
+}
+
+impl<const Q: u64> Ring for Zq<Q> {
+    const BITS: u32 = {
+        assert!(Q >= 2 && Q < 1 << 63, "the modulus must be in [2, 2^63)");
+        u64::BITS - (Q - 1).leading_zeros()
+    };
+
+    fn modulus() -> u128 { Q as u128 }
+
+    fn zero() -> Self { Zq(0) }
+    fn add(self, other: Self) -> Self { Zq((self.0 + other.0) % Q) }
+    fn mul(self, other: Self) -> Self { Zq(((self.0 as u128 * other.0 as u128) % Q as u128) as u64) }
+    fn neg(self) -> Self { Zq((Q - self.0) % Q) }
+    fn sub(self, other: Self) -> Self { Zq((self.0 + Q - other.0) % Q) }
+
+    fn from_i64(x: i64) -> Self { Zq(x.rem_euclid(Q as i64) as u64) }
+
+    fn to_u64(self) -> u64 { self.0 }
+
+    fn from_u64(x: u64) -> Option<Self> {
+        if x < Q { Some(Zq(x)) } else { None }
+    }
+
+    fn sample<G: RngCore>(rng: &mut G) -> Self {
+        // Rejection sampling keeps the distribution uniform.
+        let mask = (1 << Self::BITS) - 1;
+        loop {
+            let x = rng.next_u64() & mask;
+            if x < Q {
+                break Zq(x)
+            }
+        }
+    }
+}
+
+// Writes vectors of ring elements with exactly `R::BITS` bits per element,
+// least significant bits first, and returns their length along with the bytes.
+pub fn pack<R: Ring>(v: &[R]) -> (u64, Vec<u8>) {
+    let mut bytes = Vec::with_capacity((v.len() * R::BITS as usize).div_ceil(8));
+    let (mut acc, mut n) = (0u128, 0);
+    for x in v {
+        acc |= (x.to_u64() as u128) << n;
+        n += R::BITS;
+        while n >= 8 {
+            bytes.push(acc as u8);
+            acc >>= 8;
+            n -= 8;
+        }
+    }
+    if n > 0 {
+        bytes.push(acc as u8);
+    }
+    (v.len() as u64, bytes)
+}
+
+pub fn unpack<R: Ring>(len: u64, bytes: &[u8]) -> Result<Vec<R>, &'static str> {
+    let len = usize::try_from(len).map_err(|_| "packed vector is too long")?;
+    if len.checked_mul(R::BITS as usize).map(|n| n.div_ceil(8)) != Some(bytes.len()) {
+        return Err("packed vector has the wrong size")
+    }
+    let mask = u128::MAX >> (128 - R::BITS);
+    let mut bytes = bytes.iter();
+    let (mut acc, mut n) = (0u128, 0);
+    (0..len).map(|_| {
+        while n < R::BITS {
+            acc |= (bytes.next().copied().unwrap_or(0) as u128) << n;
+            n += 8;
+        }
+        let x = (acc & mask) as u64;
+        acc >>= R::BITS;
+        n -= R::BITS;
+        R::from_u64(x).ok_or("packed element out of range")
+    }).collect()
+}
+
+// Serializes vectors of ring elements with `pack`, for use with
+// `#[serde(with = "packed")]`.
+pub mod packed {
+    use super::*;
+
+    pub fn serialize<R: Ring, S: Serializer>(v: &[R], serializer: S) -> Result<S::Ok, S::Error> {
+        pack(v).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, R: Ring, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<R>, D::Error> {
+        use serde::de::Error;
+
+        let (len, bytes) = <(u64, Vec<u8>)>::deserialize(deserializer)?;
+        unpack(len, &bytes).map_err(D::Error::custom)
+    }
+}
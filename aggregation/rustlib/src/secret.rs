@@ -0,0 +1,81 @@
+use std::fmt;
+
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::error::*;
+
+// Secret key material, wiped from memory when dropped. It is neither `Clone`
+// nor `Copy`, so that it is not duplicated by mistake, and its `Debug`
+// implementation does not print it.
+pub struct Secret<const N: usize>([u8; N]);
+
+impl<const N: usize> Secret<N> {
+    // Only wipes the copy of `bytes` it was given: when the caller holds on
+    // to them, `copy_from` leaves no other copy behind.
+    pub fn new(mut bytes: [u8; N]) -> Self {
+        let secret = Secret(bytes);
+        bytes.zeroize();
+        secret
+    }
+
+    // A secret to be filled in place, e.g. by libsodium.
+    pub fn zero() -> Self {
+        Secret([0; N])
+    }
+
+    // Copies `bytes` into a zeroed secret, in place.
+    pub fn copy_from(bytes: &[u8; N]) -> Self {
+        let mut secret = Secret::zero();
+        secret.0.copy_from_slice(bytes);
+        secret
+    }
+
+    pub fn random() -> Result<Self, AggregationError> {
+        let mut secret = Secret::zero();
+        getrandom::getrandom(&mut secret.0).map_err(|_| ErrorKind::Randomness)?;
+        Ok(secret)
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, AggregationError> {
+        if bytes.len() != N {
+            return Err(ErrorKind::LengthMismatch { expected: N, received: bytes.len() }.into())
+        }
+        let mut secret = Secret::zero();
+        secret.0.copy_from_slice(bytes);
+        Ok(secret)
+    }
+
+    pub fn expose(&self) -> &[u8; N] {
+        &self.0
+    }
+
+    pub fn expose_mut(&mut self) -> &mut [u8; N] {
+        &mut self.0
+    }
+}
+
+impl<const N: usize> Drop for Secret<N> {
+    fn drop(&mut self) {
+        self.0[..].zeroize();
+    }
+}
+
+impl<const N: usize> fmt::Debug for Secret<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret<{}>(..)", N)
+    }
+}
+
+impl<const N: usize> Serialize for Secret<N> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de, const N: usize> Deserialize<'de> for Secret<N> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes = Zeroizing::new(Vec::<u8>::deserialize(deserializer)?);
+        Secret::from_slice(&bytes).map_err(serde::de::Error::custom)
+    }
+}
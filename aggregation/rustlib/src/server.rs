@@ -1,14 +1,26 @@
 
+use std::sync::Arc;
+use std::time::Instant;
 use std::num::Wrapping;
 use std::collections::{BTreeMap, BTreeSet};
+use std::collections::btree_map::Entry;
 
 use replace_with::*;
-use x25519_dalek;
-use sss_rs::wrapped_sharing::{Secret, reconstruct};
-use serde_json;
+use serde::{Serialize, Deserialize};
+use zeroize::Zeroizing;
 
+use crate::sodium_bindings::*;
+use crate::ring::Ring;
 use crate::helpers::*;
+use crate::shamir::{self, Share, Interpolation};
+use crate::graph::{SparseGraph, Neighbourhoods};
+use crate::encoding::FixedPointEncoding;
+use crate::cohort::{Manifest, SessionId};
+use crate::policy::{Clock, SystemClock, RoundPolicy, RoundStatus};
+use crate::format::{self, Kind};
+use crate::wire::{self, Header, Negotiation, Sender};
 use crate::types::*;
+use crate::error::*;
 
 // Implements the client server of *Practical Secure Aggregation
 // for Privacy-Preserving Machine Learning*, Bonowitz et. al.
@@ -17,216 +29,600 @@ use crate::types::*;
 // See this paper for the reference on what each round does.
 
 // AdvertiseKeys -- See Bonawitz et. al.
-fn round_0(c: Collector<(Signed<KAPublicKey>, Signed<KAPublicKey>)>) -> Result<(ServerOutput, BTreeMap<usize, KAPublicKey>), ()> {
+fn round_0<R: Ring>(
+    c: Collector<(Signed<KAPublicKey>, Signed<KAPublicKey>)>,
+    threshold: usize,
+    graph: Option<SparseGraph>
+) -> Result<(ServerOutput<R>, BTreeMap<usize, KAPublicKey>, Neighbourhoods), AggregationError> {
     let m = c.get()?;
-    let users = m.keys().cloned().collect::<Vec<usize>>();
+    shamir::check_parameters(threshold, m.keys())?;
+    let users = m.keys().cloned().collect::<BTreeSet<usize>>();
+    // In SecAgg+ mode, every user only gets the keys of its neighbours.
+    let neighbourhoods = match graph {
+        Some(graph) => {
+            shamir::check_parameters(graph.threshold, &users)?;
+            Neighbourhoods::random(&users, &graph)
+        },
+        None => Neighbourhoods::Complete,
+    };
     let msg = users.into_iter().map(|id| {
-            (id, UserInput::Round1(m.clone()))
+            let keys = m.iter()
+                .filter(|(u, _)| neighbourhoods.are_neighbours(id, **u))
+                .map(|(u, k)| (*u, k.clone()))
+                .collect();
+            (id, UserInput::Round1(keys))
         }).collect();
     let rand_pks = m.into_iter().map(|(u, (_, k))| (u, k.into_msg())).collect();
-    Ok((ServerOutput::Messages(msg), rand_pks))
+    Ok((ServerOutput::Messages(msg), rand_pks, neighbourhoods))
 }
 
 // ShareKeys -- See Bonawitz et. al.
-fn round_1(
+fn round_1<R: Ring>(
     c: Collector<BTreeMap<usize, CryptoMsg>>,
-    rand_pks: BTreeMap<usize, KAPublicKey>
-) -> Result<(ServerOutput, BTreeMap<usize, KAPublicKey>, BTreeSet<usize>), ()> {
+    rand_pks: BTreeMap<usize, KAPublicKey>,
+    neighbourhoods: Neighbourhoods,
+    threshold: usize
+) -> Result<(ServerOutput<R>, BTreeMap<usize, KAPublicKey>, Neighbourhoods, Iteration), AggregationError> {
     let mut maps = c.get()?;
+    // A user who left out the shares of one of its neighbours is deemed to
+    // have dropped, rather than failing the session; the others had shares
+    // for everyone, so they still have them for the users who are left.
+    let senders = maps.keys().cloned().collect::<Vec<usize>>();
+    maps.retain(|u, m| senders.iter().all(|v| !neighbourhoods.are_neighbours(*u, *v) || m.contains_key(v)));
+    if maps.len() < threshold {
+        return Err(ErrorKind::BelowThreshold { threshold, received: maps.len() }.into())
+    }
     let users = maps.keys().cloned().collect::<Vec<usize>>();
     let msgs = users.iter().map(|v| {
-        Ok((*v, UserInput::Round2(maps.iter_mut().map(|(u, m)| Ok((*u, m.remove(&v).ok_or(())?))).collect::<Result<_, ()>>()?)))
-    }).collect::<Result<BTreeMap<usize, UserInput>, ()>>()?;
-    Ok((ServerOutput::Messages(msgs), rand_pks, users.into_iter().collect()))
+        let senders = maps.iter_mut().filter(|(u, _)| neighbourhoods.are_neighbours(**u, *v));
+        (*v, UserInput::Round2(senders.filter_map(|(u, m)| Some((*u, m.remove(v)?))).collect()))
+    }).collect();
+    let iteration = Iteration { index: 0, participants: users.into_iter().collect() };
+    Ok((ServerOutput::Messages(msgs), rand_pks, neighbourhoods, iteration))
 }
 
 // MaskedInputCollection -- See Bonawitz et. al.
-fn round_2(
-    c: Collector<Vec<Wrapping<i64>>>,
+fn round_2<R: Ring>(
+    acc: Accumulator<R>,
     rand_pks: BTreeMap<usize, KAPublicKey>,
-    sharing_users: BTreeSet<usize>
-) -> Result<(ServerOutput, BTreeMap<usize, KAPublicKey>, BTreeSet<usize>, Vec<Vec<Wrapping<i64>>>, BTreeSet<usize>), ()> {
-    let vecs = c.get()?;
-    let users = vecs.keys().cloned().collect::<Vec<usize>>();
+    neighbourhoods: Neighbourhoods,
+    iteration: Iteration
+) -> Result<(ServerOutput<R>, BTreeMap<usize, KAPublicKey>, Neighbourhoods, Iteration, Vec<R>, BTreeSet<usize>), AggregationError> {
+    let (alive, sum) = acc.get()?;
+    // The users would refuse to reveal the keys of those who dropped, see
+    // `user::reveal_shares`, as their seeds were revealed in an earlier iteration.
+    if iteration.index > 0 && alive.len() < iteration.participants.len() {
+        let kind = ErrorKind::BelowThreshold { threshold: iteration.participants.len(), received: alive.len() };
+        return Err(kind.into())
+    }
+    let users = alive.iter().cloned().collect::<Vec<usize>>();
     let msgs = users.iter().map(|u| (*u, UserInput::Round3(users.clone()))).collect();
-    Ok((ServerOutput::Messages(msgs), rand_pks, sharing_users, vecs.into_values().collect(), users.into_iter().collect()))
+    Ok((ServerOutput::Messages(msgs), rand_pks, neighbourhoods, iteration, sum, alive))
 }
 
 // ConsistencyCheck -- See Bonawitz et. al.
-fn round_3(
+fn round_3<R: Ring>(
     c: Collector<BundledSignature>,
     rand_pks: BTreeMap<usize, KAPublicKey>,
-    sharing_users: BTreeSet<usize>,
-    vecs: Vec<Vec<Wrapping<i64>>>,
+    neighbourhoods: Neighbourhoods,
+    iteration: Iteration,
+    sum: Vec<R>,
     alive: BTreeSet<usize>,
-) -> Result<(ServerOutput, BTreeMap<usize, KAPublicKey>, BTreeSet<usize>, Vec<Vec<Wrapping<i64>>>, BTreeSet<usize>), ()> {
+) -> Result<(ServerOutput<R>, BTreeMap<usize, KAPublicKey>, Neighbourhoods, Iteration, Vec<R>, BTreeSet<usize>), AggregationError> {
     let m = c.get()?;
     let users = m.keys().cloned().collect::<Vec<usize>>();
     let msg = users.into_iter().map(|id| {
             (id, UserInput::Round4(m.clone()))
         }).collect();
-    Ok((ServerOutput::Messages(msg), rand_pks, sharing_users, vecs, alive))
+    Ok((ServerOutput::Messages(msg), rand_pks, neighbourhoods, iteration, sum, alive))
 }
 
 // Unmasking -- See Bonawitz et. al.
-fn round_4(
+#[allow(clippy::too_many_arguments)]
+fn round_4<R: Ring>(
     c: Collector<BTreeMap<usize, RevealedShare>>,
     rand_pks: BTreeMap<usize, KAPublicKey>,
-    sharing_users: BTreeSet<usize>,
-    vecs: Vec<Vec<Wrapping<i64>>>,
+    neighbourhoods: Neighbourhoods,
+    iteration: Iteration,
+    sum: Vec<R>,
     alive: BTreeSet<usize>,
-    vec_len: usize,
-)   -> Result<(ServerOutput, ()), ()> {
+    share_threshold: usize,
+    weighted: bool,
+)   -> Result<(ServerOutput<R>, BTreeMap<usize, KAPublicKey>, Neighbourhoods, Iteration), AggregationError> {
     let mut m = c.get()?;
-    let dropped = sharing_users.difference(&alive).cloned().collect::<BTreeSet<usize>>();
-    
+    let dropped = iteration.participants.difference(&alive).cloned().collect::<BTreeSet<usize>>();
+    // The users who dropped before unmasking cannot take part in the next
+    // iteration, and neither can the users whose keys are being revealed.
+    let next = Iteration { index: iteration.index + 1, participants: m.keys().cloned().collect() };
+
+    // Every secret is recovered from exactly `share_threshold` shares, those
+    // of the responders with the lowest ids who revealed one, so that the Lagrange
+    // coefficients are only computed once per set of responders: once in
+    // all, unless in SecAgg+ mode.
+    let mut interpolations: BTreeMap<Vec<usize>, Interpolation> = BTreeMap::new();
+    let mut reconstruct_secret = |u: usize, mut shares: Vec<(usize, Share)>| {
+        // Only the neighbours of a user hold shares of its secrets.
+        if shares.len() < share_threshold {
+            let kind = ErrorKind::BelowThreshold { threshold: share_threshold, received: shares.len() };
+            return Err(AggregationError::new(kind).for_user(u))
+        }
+        // Blame the responder for a share which isn't bound to its id,
+        // and the user whose secret is at stake otherwise.
+        let blame = |e: AggregationError| match e.user {
+            Some(_) => e,
+            None => e.for_user(u),
+        };
+        shares.truncate(share_threshold);
+        let ids = shares.iter().map(|(r, _)| *r).collect::<Vec<usize>>();
+        let interpolation = match interpolations.entry(ids) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let interpolation = Interpolation::new(e.key()).map_err(blame)?;
+                e.insert(interpolation)
+            },
+        };
+        interpolation.reconstruct(&shares, 32).map_err(blame)
+    };
+
+    // A responder who left out a share, or revealed the wrong secret, is
+    // skipped, as long as enough others did reveal the right one.
     let alive_shares = alive.iter().map(|u| {
-        let shares = m.iter_mut().map(|(_, m)| match m.remove(u).ok_or(())? {
-            RevealedShare::Seed(s) => Ok(s),
-            RevealedShare::RandSk(_) => Err(()),
-        }).collect::<Result<_, ()>>()?;
-        Ok((*u, shares))
-    }).collect::<Result<BTreeMap<usize, Vec<Vec<u8>>>, ()>>()?;
+        let shares = m.iter_mut().filter(|(r, _)| neighbourhoods.are_neighbours(**r, *u)).filter_map(|(r, m)| match m.remove(u)? {
+            RevealedShare::Seed(s) => Some((*r, s)),
+            RevealedShare::RandSk(_) => None,
+        }).collect();
+        (*u, shares)
+    }).collect::<BTreeMap<usize, Vec<(usize, Share)>>>();
     let alive_secrets: BTreeMap<usize, Vec<u8>> = alive_shares.into_iter()
-        .map(|(u, shares)| {
-            let mut s = Secret::empty_in_memory();
-            reconstruct(&mut s, shares, true).map_err(|_| ())?;
-            Ok((u, s.try_unwrap_vec().ok_or(())?))
-        }).collect::<Result<_, ()>>()?;
-    let alive_contribution: Vec<Vec<Wrapping<i64>>> = alive_secrets.into_iter().map(|(_, seed)| {
-        Ok(scalar_mul(Wrapping(-1), vector_from_seed(seed.try_into().map_err(|_| ())?, vec_len)))
-    }).collect::<Result<_, ()>>()?;
-    
+        .map(|(u, shares)| Ok((u, reconstruct_secret(u, shares)?)))
+        .collect::<Result<_, AggregationError>>()?;
+    let alive_seeds: Vec<[u8; 32]> = alive_secrets.into_iter().map(|(u, seed)| {
+        seed.try_into().map_err(|_| AggregationError::new(ErrorKind::ShareReconstruction).for_user(u))
+    }).collect::<Result<_, AggregationError>>()?;
+
     let dropped_shares = dropped.iter().map(|u| {
-        let shares = m.iter_mut().map(|(_, m)| match m.remove(u).ok_or(())? {
-            RevealedShare::Seed(_) => Err(()),
-            RevealedShare::RandSk(s) => Ok(s),
-        }).collect::<Result<_, ()>>()?;
-        Ok((*u, shares))
-    }).collect::<Result<BTreeMap<usize, Vec<Vec<u8>>>, ()>>()?;
-    let dropped_secrets: BTreeMap<usize, Vec<u8>> = dropped_shares.into_iter()
-        .map(|(u, shares)| {
-            let mut s = Secret::empty_in_memory();
-            reconstruct(&mut s, shares, true).map_err(|_| ())?;
-            Ok((u, s.try_unwrap_vec().ok_or(())?))
-        }).collect::<Result<_, ()>>()?;
-    let dropped_contribution: Vec<Vec<Wrapping<i64>>> = dropped_secrets.into_iter().map(|(u, secret)| {
-        let rand_sk = secret.try_into().map_err(|_| ())?;
-        let masks: Vec<Vec<Wrapping<i64>>> = alive.iter().map(|v| {
-            let other_rand_pk = rand_pks.get(v).ok_or(())?;
-            let common_seed = x25519_dalek::x25519(rand_sk, other_rand_pk.clone());
-
-            use std::cmp::Ordering;
-            let l = match usize::cmp(v, &u) {
-                Ordering::Less => 1,
-                Ordering::Equal => 0,
-                Ordering::Greater => -1,
-            };
-            Ok(scalar_mul(Wrapping(l), vector_from_seed(common_seed, vec_len)))
-        }).collect::<Result<_, ()>>()?;
-        Ok(sum_components(masks.into_iter(), vec_len))
-    }).collect::<Result<_, ()>>()?;
-
-    let res = sum_components(
-        Iterator::chain(alive_contribution.into_iter(), dropped_contribution.into_iter()).chain(vecs.into_iter()),
-        vec_len
-    );
-
-    Ok((ServerOutput::Vector(res), ()))
+        let shares = m.iter_mut().filter(|(r, _)| neighbourhoods.are_neighbours(**r, *u)).filter_map(|(r, m)| match m.remove(u)? {
+            RevealedShare::Seed(_) => None,
+            RevealedShare::RandSk(s) => Some((*r, s)),
+        }).collect();
+        (*u, shares)
+    }).collect::<BTreeMap<usize, Vec<(usize, Share)>>>();
+    let dropped_keys: Vec<(usize, KASecretKey)> = dropped_shares.into_iter().map(|(u, shares)| {
+        let secret = Zeroizing::new(reconstruct_secret(u, shares)?);
+        let rand_sk = KASecretKey::from_slice(&secret)
+            .map_err(|_| AggregationError::new(ErrorKind::ShareReconstruction).for_user(u))?;
+        Ok((u, rand_sk))
+    }).collect::<Result<_, AggregationError>>()?;
+
+    let mut masks: Vec<(Sign, [u8; 32])> = alive_seeds.into_iter().map(|seed| (Sign::Minus, seed)).collect();
+    for (u, rand_sk) in dropped_keys {
+        for v in alive.iter().filter(|v| **v != u && neighbourhoods.are_neighbours(u, **v)) {
+            let other_rand_pk = rand_pks.get(v)
+                .ok_or_else(|| AggregationError::new(ErrorKind::UnknownUser).for_user(*v))?;
+            let common_seed = iteration_seed(&key_agreement(&rand_sk, other_rand_pk), iteration.index);
+            let sign = if *v < u { Sign::Plus } else { Sign::Minus };
+            masks.push((sign, common_seed));
+        }
+    }
+
+    // Unmask the sum in place, without materialising any mask.
+    let mut res = sum;
+    apply_masks(&mut res, &masks);
+    wipe_masks(&mut masks);
+    // In weighted mode, the total weight comes last.
+    let weight = if weighted { res.pop() } else { None };
+
+    Ok((ServerOutput::Aggregate(Aggregate { vec: res, weight, users: alive, equivocators: BTreeSet::new() }), rand_pks, neighbourhoods, next))
+}
+
+// Starts the next iteration of a session.
+fn round_next<R: Ring>(
+    iteration: Iteration,
+    threshold: usize
+) -> Result<(ServerOutput<R>, Iteration), AggregationError> {
+    let n = iteration.participants.len();
+    if n < threshold {
+        return Err(ErrorKind::BelowThreshold { threshold, received: n }.into())
+    }
+    let users = iteration.participants.iter().cloned().collect::<Vec<usize>>();
+    let msgs = users.iter().map(|u| (*u, UserInput::NextIteration(users.clone()))).collect();
+    Ok((ServerOutput::Messages(msgs), iteration))
+}
+
+fn fail_server<T, R: Ring>(e: AggregationError) -> (Result<T, AggregationError>, ServerState<R>) {
+    (Err(e.clone()), ServerState::Failed(e))
+}
+
+// The masked inputs summed so far must have the length of the inputs.
+fn check_sum_len<R: Ring>(state: &ServerState<R>, input_len: usize) -> Result<(), AggregationError> {
+    let sum_len = match state {
+        ServerState::Round2(acc, ..) => Some(acc.dim()),
+        ServerState::Round3(_, _, _, _, sum, _) | ServerState::Round4(_, _, _, _, sum, _) => Some(sum.len()),
+        _ => None,
+    };
+    match sum_len {
+        Some(len) if len != input_len => Err(ErrorKind::LengthMismatch { expected: input_len, received: len }.into()),
+        _ => Ok(()),
+    }
+}
+
+// Generic so as to be written from a reference to the state.
+#[derive(Serialize, Deserialize)]
+struct ServerSnapshot<S> {
+    threshold: usize,
+    vec_len: usize,
+    weighted: bool,
+    graph: Option<SparseGraph>,
+    iterations: usize,
+    threat_model: ThreatModel,
+    policy: Option<RoundPolicy>,
+    expected: BTreeSet<usize>,
+    equivocators: BTreeSet<usize>,
+    sign_pks: Arc<BTreeMap<usize, SignPublicKey>>,
+    session_id: SessionId,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    legacy_wire: bool,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    wire_versions: BTreeMap<usize, u32>,
+    state: S,
+}
+
+// What `serialize_state` writes: the users who equivocated in the rounds
+// already over go along with the state, as their collectors are gone.
+#[derive(Serialize, Deserialize)]
+struct PersistedState<S> {
+    equivocators: BTreeSet<usize>,
+    state: S,
 }
 
-pub struct Server {
+pub struct Server<R: Ring = Wrapping<i64>> {
     threshold: usize,
     vec_len: usize,
-    state: ServerState,
+    weighted: bool,
+    graph: Option<SparseGraph>,
+    iterations: usize,
+    threat_model: ThreatModel,
+    policy: Option<RoundPolicy>,
+    clock: Arc<dyn Clock>,
+    // When the current round started, and the users asked to answer in it,
+    // which are the sets U1 to U4 of Bonawitz et. al. past Round 0.
+    round_started: Instant,
+    expected: BTreeSet<usize>,
+    // Users who sent conflicting messages since the last aggregate.
+    equivocators: BTreeSet<usize>,
+    sign_pks: Arc<BTreeMap<usize, SignPublicKey>>,
+    session_id: SessionId,
+    // Whether frames of older versions are read, and the version of the
+    // first frame of each user, the only one read from it afterwards.
+    legacy_wire: bool,
+    wire_versions: BTreeMap<usize, u32>,
+    state: ServerState<R>,
 }
 
-impl Server {
-    pub fn new(threshold: usize, vec_len: usize) -> Self {
-        Server { threshold, vec_len, state: ServerState::Round0(Collector::new(threshold)) }
+impl<R: Ring> Server<R> {
+    // The inputs of all the users, once encoded with `encoding`, must sum
+    // without overflowing the ring.
+    pub fn new(
+        threshold: usize,
+        vec_len: usize,
+        sign_pks: Arc<BTreeMap<usize, SignPublicKey>>,
+        encoding: &FixedPointEncoding<R>,
+    ) -> Result<Self, AggregationError> {
+        encoding.check_contributors(sign_pks.len())?;
+        let clock = Arc::new(SystemClock);
+        Ok(Server {
+            threshold, vec_len,
+            weighted: false,
+            graph: None,
+            iterations: 1,
+            threat_model: ThreatModel::Malicious,
+            policy: None,
+            round_started: clock.now(),
+            clock,
+            expected: sign_pks.keys().cloned().collect(),
+            equivocators: BTreeSet::new(),
+            sign_pks,
+            session_id: SessionId::default(),
+            legacy_wire: false,
+            wire_versions: BTreeMap::new(),
+            state: ServerState::Round0(Collector::new(threshold)),
+        })
     }
 
-    pub fn serialize_state(&self) -> Result<String, ()> {
-        serde_json::to_string(&self.state).map_err(|_| ())
+    // For the cohort of a manifest published by a `cohort::Registrar`.
+    pub fn from_manifest(manifest: &Manifest, encoding: &FixedPointEncoding<R>) -> Result<Self, AggregationError> {
+        let server = Server::new(manifest.threshold, manifest.vec_len, Arc::new(manifest.members.clone()), encoding)?;
+        Ok(server.with_session_id(manifest.session_id))
     }
 
-    pub fn recover_state(&mut self, s: &str) -> Result<(), ()> {
-        self.state = serde_json::from_str(s).map_err(|_| ())?;
-        Ok(())
+    // Aggregates the inputs of users created with `User::new_weighted`,
+    // along with their total weight.
+    pub fn new_weighted(
+        threshold: usize,
+        vec_len: usize,
+        sign_pks: Arc<BTreeMap<usize, SignPublicKey>>,
+        encoding: &FixedPointEncoding<R>,
+    ) -> Result<Self, AggregationError> {
+        Ok(Server { weighted: true, ..Server::new(threshold, vec_len, sign_pks, encoding)? })
+    }
+
+    // Runs SecAgg+ over a random graph, for users set up with
+    // `User::with_sparse_graph` and `graph.threshold` as their threshold.
+    pub fn with_sparse_graph(mut self, graph: SparseGraph) -> Self {
+        self.graph = Some(graph);
+        self
+    }
+
+    // Runs a session of several iterations, which share the keys set up
+    // in Rounds 0 and 1: once an iteration has given its aggregate, the next
+    // one starts with the users who took part in all of its rounds.
+    // As the keys are shared, only the first iteration tolerates users who
+    // drop before sending their masked input.
+    pub fn with_iterations(mut self, iterations: usize) -> Self {
+        self.iterations = iterations;
+        self
+    }
+
+    // With `ThreatModel::SemiHonest`, Round 3 is skipped, which saves a
+    // round trip but lets a malicious server unmask some inputs.
+    pub fn with_threat_model(mut self, threat_model: ThreatModel) -> Self {
+        self.threat_model = threat_model;
+        self
+    }
+
+    // Lets `status` tell when to close a round, see `policy::RoundPolicy`.
+    pub fn with_policy(mut self, policy: RoundPolicy) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    // Which session the frames of `round_serialized` belong to.
+    pub fn with_session_id(mut self, session_id: SessionId) -> Self {
+        self.session_id = session_id;
+        self
+    }
+
+    pub fn session_id(&self) -> &SessionId {
+        &self.session_id
+    }
+
+    // Also reads frames of the versions before `wire::WIRE_VERSION`, from
+    // users who write them.
+    pub fn with_legacy_wire(mut self) -> Self {
+        self.legacy_wire = true;
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.round_started = clock.now();
+        self.clock = clock;
+        self
     }
 
-    pub fn recv_serialized(&mut self, id: usize, msg: &[u8]) -> Result<(), ()> {
-        match bincode::deserialize::<UserOutput>(msg) {
-            Ok(msg) => { self.recv(id, msg)?; Ok(()) },
-            Err(_) => Err(())
+    // Whether the current round can be closed with `round`. Without a
+    // policy, it can as soon as `threshold` users answered.
+    pub fn status(&self) -> RoundStatus {
+        let received = match &self.state {
+            ServerState::Round0(c) => c.len(),
+            ServerState::Round1(c, ..) => c.len(),
+            ServerState::Round2(acc, ..) => acc.len(),
+            ServerState::Round3(c, ..) => c.len(),
+            ServerState::Round4(c, ..) => c.len(),
+            ServerState::Idle(..) => return RoundStatus::Ready,
+            ServerState::Done => return RoundStatus::Done,
+            ServerState::Failed(e) => return RoundStatus::Failed(e.clone()),
+        };
+        let status = match &self.policy {
+            Some(policy) => {
+                let elapsed = self.clock.now().saturating_duration_since(self.round_started);
+                policy.status(self.threshold, self.expected.len(), received, elapsed)
+            },
+            None if received >= self.threshold => RoundStatus::Ready,
+            None => RoundStatus::Wait,
+        };
+        match status {
+            RoundStatus::Failed(e) => RoundStatus::Failed(e.at_round(self.state.round())),
+            status => status,
         }
     }
 
-    pub fn round_serialized(&mut self) -> Result<ServerOutputSerialized, ()> {
-        match self.round() {
-            Ok(ServerOutput::Messages(res)) =>
+    // Length of the masked inputs: in weighted mode, the weight comes last.
+    fn input_len(&self) -> usize {
+        if self.weighted { self.vec_len + 1 } else { self.vec_len }
+    }
+
+    // The whole server, configuration included, to be restored with
+    // `from_snapshot` e.g. after a crash. The clock is not part of it, and
+    // the current round is deemed to start when the snapshot is restored.
+    pub fn snapshot(&self) -> Result<Vec<u8>, AggregationError> {
+        let snapshot = ServerSnapshot {
+            threshold: self.threshold,
+            vec_len: self.vec_len,
+            weighted: self.weighted,
+            graph: self.graph,
+            iterations: self.iterations,
+            threat_model: self.threat_model,
+            policy: self.policy.clone(),
+            expected: self.expected.clone(),
+            equivocators: self.equivocators.clone(),
+            sign_pks: Arc::clone(&self.sign_pks),
+            session_id: self.session_id,
+            legacy_wire: self.legacy_wire,
+            wire_versions: self.wire_versions.clone(),
+            state: &self.state,
+        };
+        format::wrap(Kind::ServerSnapshot, &snapshot).map(String::into_bytes)
+    }
+
+    // Fails on a snapshot whose configuration is not consistent, rather
+    // than resuming a session which could only go wrong.
+    pub fn from_snapshot(bytes: &[u8]) -> Result<Self, AggregationError> {
+        let snapshot: ServerSnapshot<ServerState<R>> = format::unwrap(Kind::ServerSnapshot, bytes)?;
+        let ServerSnapshot {
+            threshold, vec_len, weighted, graph, iterations, threat_model, policy, expected, equivocators, sign_pks, session_id,
+            legacy_wire, wire_versions, state,
+        } = snapshot;
+        shamir::check_parameters(threshold, sign_pks.keys())?;
+        let clock = Arc::new(SystemClock);
+        let server = Server {
+            threshold, vec_len, weighted, graph, iterations, threat_model, policy,
+            round_started: clock.now(),
+            clock,
+            expected, equivocators, sign_pks, session_id, legacy_wire, wire_versions, state,
+        };
+        check_sum_len(&server.state, server.input_len())?;
+        Ok(server)
+    }
+
+    pub fn serialize_state(&self) -> Result<String, AggregationError> {
+        format::wrap(Kind::ServerState, &PersistedState { equivocators: self.equivocators.clone(), state: &self.state })
+    }
+
+    pub fn recover_state(&mut self, s: &str) -> Result<(), AggregationError> {
+        let persisted: PersistedState<ServerState<R>> = format::unwrap(Kind::ServerState, s.as_bytes())?;
+        check_sum_len(&persisted.state, self.input_len())?;
+        self.equivocators = persisted.equivocators;
+        self.state = persisted.state;
+        Ok(())
+    }
+
+    // Takes a frame from user `id`, see `wire`. The version of the first
+    // frame of a user is the only one read from it afterwards.
+    pub fn recv_serialized(&mut self, id: usize, msg: &[u8]) -> Result<(), AggregationError> {
+        let round = self.state.round();
+        let negotiation = Negotiation { legacy: self.legacy_wire, version: self.wire_versions.get(&id).copied() };
+        let (header, msg) = wire::decode(msg, &self.session_id, round, Sender::User(id), &negotiation)
+            .map_err(|e| e.at_round(round).for_user(id))?;
+        self.recv(id, msg)?;
+        self.wire_versions.entry(id).or_insert(header.version);
+        Ok(())
+    }
+
+    pub fn round_serialized(&mut self) -> Result<ServerOutputSerialized<R>, AggregationError> {
+        match self.round()? {
+            ServerOutput::Messages(res) =>
                 Ok(ServerOutputSerialized::Messages(
                         res.into_iter()
-                        .map(|(k, v)| Ok((k, bincode::serialize(&v).map_err(|_| ())?))).collect::<Result<_, ()>>()?)),
-            Ok(ServerOutput::Vector(v)) => Ok(ServerOutputSerialized::Vector(v)),
-            Err(()) => Err(())
+                        .map(|(k, v)| Ok((k, wire::encode(&Header::new(self.session_id, v.round(), Sender::Server), &v)?)))
+                        .collect::<Result<_, AggregationError>>()?)),
+            ServerOutput::Aggregate(a) => Ok(ServerOutputSerialized::Aggregate(a)),
         }
     }
 
-    pub fn recv(&mut self, id: usize, msg: UserOutput) -> Result<(), ()> {
-        match (&mut self.state, msg) {
-            (ServerState::Round0(c), UserOutput::Round0(x, y)) => c.recv(id, (x, y)),
-            (ServerState::Round1(c, _), UserOutput::Round1(x)) => c.recv(id, x),
-            (ServerState::Round2(c, _, _), UserOutput::Round2(x)) => c.recv(id, x),
-            (ServerState::Round3(c, _, _, _, _), UserOutput::Round3(x)) => c.recv(id, x),
-            (ServerState::Round4(c, _, _, _, _), UserOutput::Round4(x)) => c.recv(id, x),
-            _ => Err(())?
+    pub fn recv(&mut self, id: usize, msg: UserOutput<R>) -> Result<(), AggregationError> {
+        let round = self.state.round();
+        // Only the users the server wrote to may answer, so that nobody
+        // joins the session halfway through.
+        if round.is_some_and(|r| r > 0) && !self.expected.contains(&id) {
+            return Err(AggregationError::new(ErrorKind::UnexpectedSender).at_round(round).for_user(id))
+        }
+        let res = match (&mut self.state, msg) {
+            (ServerState::Round0(c), UserOutput::Round0(x, y)) => {
+                // A forged advertisement would make every honest user fail
+                // in Round 1, so we drop it before it gets broadcast.
+                let sign_pk = self.sign_pks.get(&id)
+                    .ok_or_else(|| AggregationError::new(ErrorKind::UnknownUser).at_round(round).for_user(id))?;
+                x.verify(sign_pk).and(y.verify(sign_pk))
+                    .map_err(|e| e.at_round(round).for_user(id))?;
+                c.recv(id, (x, y))
+            },
+            (ServerState::Round1(c, _, _), UserOutput::Round1(x)) => c.recv(id, x),
+            (ServerState::Round2(acc, _, _, _), UserOutput::Round2(x)) => acc.recv(id, x),
+            (ServerState::Round3(c, _, _, _, _, _), UserOutput::Round3(x)) => c.recv(id, x),
+            (ServerState::Round4(c, _, _, _, _, _), UserOutput::Round4(x)) => c.recv(id, x),
+            _ => Err(AggregationError::new(ErrorKind::UnexpectedRound).for_user(id)),
         };
-        Ok(())
+        res.map_err(|e| {
+            if e.kind == ErrorKind::Equivocation {
+                self.equivocators.insert(id);
+            }
+            e.at_round(round)
+        })
+    }
+
+    // The users who sent conflicting messages since the last aggregate.
+    pub fn equivocators(&self) -> &BTreeSet<usize> {
+        &self.equivocators
     }
 
-    pub fn round(&mut self) -> Result<ServerOutput, ()> {
-        replace_with_or_abort_and_return(&mut self.state, |state| {
+    pub fn round(&mut self) -> Result<ServerOutput<R>, AggregationError> {
+        let input_len = self.input_len();
+        let mut output = replace_with_or_abort_and_return(&mut self.state, |state| {
+            let round = state.round();
             match state {
                 ServerState::Round0(c) => {
-                    match round_0(c) {
-                        Ok((output, rand_pks)) =>
-                            (Ok(output), ServerState::Round1(Collector::new(self.threshold), rand_pks)),
-                        Err(()) => (Err(()), ServerState::Failed),
+                    match round_0(c, self.threshold, self.graph) {
+                        Ok((output, rand_pks, neighbourhoods)) =>
+                            (Ok(output), ServerState::Round1(Collector::new(self.threshold), rand_pks, neighbourhoods)),
+                        Err(e) => fail_server(e.at_round(round)),
                     }
                 },
-                ServerState::Round1(c, rand_pks) => {
-                    match round_1(c, rand_pks) {
-                        Ok((output, rand_pks, sharing_users)) =>
-                            (Ok(output), ServerState::Round2(Collector::new(self.threshold), rand_pks, sharing_users)),
-                        Err(()) => (Err(()), ServerState::Failed),
+                ServerState::Round1(c, rand_pks, neighbourhoods) => {
+                    match round_1(c, rand_pks, neighbourhoods, self.threshold) {
+                        Ok((output, rand_pks, neighbourhoods, iteration)) =>
+                            (Ok(output), ServerState::Round2(Accumulator::new(self.threshold, input_len), rand_pks, neighbourhoods, iteration)),
+                        Err(e) => fail_server(e.at_round(round)),
                     }
                 },
-                ServerState::Round2(c, rand_pks, sharing_users) => {
-                    match round_2(c, rand_pks, sharing_users,) {
-                        Ok((output, rand_pks, sharing_users, vecs, alive)) =>
-                            (Ok(output), ServerState::Round3(Collector::new(self.threshold), rand_pks, sharing_users, vecs, alive)),
-                        Err(()) => (Err(()), ServerState::Failed)
+                ServerState::Round2(c, rand_pks, neighbourhoods, iteration) => {
+                    match round_2(c, rand_pks, neighbourhoods, iteration) {
+                        Ok((output, rand_pks, neighbourhoods, iteration, sum, alive)) => {
+                            let state = match self.threat_model {
+                                ThreatModel::Malicious =>
+                                    ServerState::Round3(Collector::new(self.threshold), rand_pks, neighbourhoods, iteration, sum, alive),
+                                ThreatModel::SemiHonest =>
+                                    ServerState::Round4(Collector::new(self.threshold), rand_pks, neighbourhoods, iteration, sum, alive),
+                            };
+                            (Ok(output), state)
+                        },
+                        Err(e) => fail_server(e.at_round(round))
                     }
                 },
-                ServerState::Round3(c, rand_pks, sharing_users, vecs, alive) => {
-                    match round_3(c, rand_pks, sharing_users, vecs, alive) {
-                        Ok((output, rand_pks, sharing_users, vecs, alive)) =>
-                            (Ok(output), ServerState::Round4(Collector::new(self.threshold), rand_pks, sharing_users, vecs, alive)),
-                        Err(()) => (Err(()), ServerState::Failed)
+                ServerState::Round3(c, rand_pks, neighbourhoods, iteration, sum, alive) => {
+                    match round_3(c, rand_pks, neighbourhoods, iteration, sum, alive) {
+                        Ok((output, rand_pks, neighbourhoods, iteration, sum, alive)) =>
+                            (Ok(output), ServerState::Round4(Collector::new(self.threshold), rand_pks, neighbourhoods, iteration, sum, alive)),
+                        Err(e) => fail_server(e.at_round(round))
                     }
                 },
-                ServerState::Round4(c, rand_pks, sharing_users, vecs, alive) => {
-                    match round_4(c, rand_pks, sharing_users, vecs, alive, self.vec_len) {
-                        Ok((output, ())) =>
+                ServerState::Round4(c, rand_pks, neighbourhoods, iteration, sum, alive) => {
+                    let share_threshold = match (self.graph, &neighbourhoods) {
+                        (Some(graph), Neighbourhoods::Sparse(_)) => graph.threshold,
+                        // A graph too dense for the cohort falls back to a
+                        // complete one, and so to the threshold of the session.
+                        (Some(graph), Neighbourhoods::Complete) => usize::max(graph.threshold, self.threshold),
+                        (None, _) => self.threshold,
+                    };
+                    match round_4(c, rand_pks, neighbourhoods, iteration, sum, alive, share_threshold, self.weighted) {
+                        Ok((output, rand_pks, neighbourhoods, next)) if next.index < self.iterations =>
+                            (Ok(output), ServerState::Idle(rand_pks, neighbourhoods, next)),
+                        Ok((output, ..)) =>
                             (Ok(output), ServerState::Done),
-                        Err(()) => (Err(()), ServerState::Failed)
+                        Err(e) => fail_server(e.at_round(round))
                     }
                 },
-                ServerState::Done => (Err(()), ServerState::Done),
-                _ => (Err(()), ServerState::Failed)
+                ServerState::Idle(rand_pks, neighbourhoods, iteration) => {
+                    match round_next(iteration, self.threshold) {
+                        Ok((output, iteration)) =>
+                            (Ok(output), ServerState::Round2(Accumulator::new(self.threshold, input_len), rand_pks, neighbourhoods, iteration)),
+                        Err(e) => fail_server(e.at_round(round))
+                    }
+                },
+                ServerState::Done =>
+                    (Err(AggregationError::new(ErrorKind::UnexpectedRound)), ServerState::Done),
+                // Once failed, keep the error that caused the failure.
+                ServerState::Failed(e) => (Err(e.clone()), ServerState::Failed(e)),
             }
-        })
+        });
+
+        // A new round starts, in which the users we just wrote to may answer.
+        match &mut output {
+            Ok(ServerOutput::Messages(m)) => self.expected = m.keys().cloned().collect(),
+            Ok(ServerOutput::Aggregate(a)) => a.equivocators = std::mem::take(&mut self.equivocators),
+            Err(_) => (),
+        }
+        self.round_started = self.clock.now();
+        output
     }
 }
 
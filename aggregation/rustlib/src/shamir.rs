@@ -0,0 +1,162 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use rand::Rng;
+use rand::rngs::OsRng;
+use serde::{Serialize, Deserialize};
+use zeroize::Zeroizing;
+
+use crate::error::*;
+
+// Shamir secret sharing over the prime field GF(2^61 - 1).
+//
+// The field is large enough to give a distinct evaluation point to every
+// user id we can meet in practice, so the number of participants is not
+// bounded by the size of the field as it is with GF(2^8).
+// Secrets are cut into 7-byte chunks, each of which is shared with its own
+// polynomial; a share holds one evaluation per chunk.
+//
+// Every share carries its evaluation point, which is bound to the id of the
+// user holding it: shares are always handled as `(id, share)` pairs and
+// never rely on the order in which they were produced or gathered.
+
+pub const PRIME: u64 = (1 << 61) - 1;
+
+const CHUNK_BYTES: usize = 7;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Share {
+    pub point: u64,
+    pub values: Vec<u64>,
+}
+
+fn add(a: u64, b: u64) -> u64 {
+    (a + b) % PRIME
+}
+
+fn sub(a: u64, b: u64) -> u64 {
+    (a + PRIME - b) % PRIME
+}
+
+fn mul(a: u64, b: u64) -> u64 {
+    ((a as u128 * b as u128) % PRIME as u128) as u64
+}
+
+fn inv(a: u64) -> u64 {
+    // Fermat's little theorem: a^(p - 2) = a^(-1) mod p.
+    let (mut base, mut exp, mut res) = (a, PRIME - 2, 1);
+    while exp > 0 {
+        if exp & 1 == 1 {
+            res = mul(res, base);
+        }
+        base = mul(base, base);
+        exp >>= 1;
+    }
+    res
+}
+
+// The evaluation point of a user is derived from its id,
+// 0 being reserved for the secret itself.
+pub fn evaluation_point(id: usize) -> Result<u64, AggregationError> {
+    match u64::try_from(id) {
+        Ok(x) if x < PRIME - 1 => Ok(x + 1),
+        _ => Err(AggregationError::new(ErrorKind::InvalidParameters).for_user(id)),
+    }
+}
+
+pub fn check_parameters<'a, I>(threshold: usize, ids: I) -> Result<(), AggregationError>
+    where I: IntoIterator<Item=&'a usize>
+{
+    let n = ids.into_iter()
+        .map(|id| evaluation_point(*id))
+        .collect::<Result<BTreeSet<u64>, _>>()?
+        .len();
+    if threshold == 0 || threshold > n {
+        Err(ErrorKind::InvalidParameters.into())
+    } else {
+        Ok(())
+    }
+}
+
+// Shares `secret` among `ids` so that any `threshold` of them can recover it.
+pub fn share(secret: &[u8], threshold: usize, ids: &[usize]) -> Result<BTreeMap<usize, Share>, AggregationError> {
+    check_parameters(threshold, ids)?;
+
+    // The constant coefficients are the chunks of the secret.
+    let polys: Zeroizing<Vec<Vec<u64>>> = Zeroizing::new(secret.chunks(CHUNK_BYTES).map(|chunk| {
+        let mut bytes = Zeroizing::new([0; 8]);
+        bytes[..chunk.len()].copy_from_slice(chunk);
+        std::iter::once(u64::from_le_bytes(*bytes))
+            .chain((1..threshold).map(|_| OsRng.gen_range(0..PRIME)))
+            .collect()
+    }).collect());
+
+    ids.iter().map(|id| {
+        let point = evaluation_point(*id)?;
+        let values = polys.iter().map(|coeffs| {
+            // Horner's method.
+            coeffs.iter().rev().fold(0, |acc, c| add(mul(acc, point), *c))
+        }).collect();
+        Ok((*id, Share { point, values }))
+    }).collect()
+}
+
+// The Lagrange coefficients for an interpolation at 0 from the shares of
+// `ids`. They only depend on who answered, so they are computed once, in
+// O(t^2) for t shares, and reused for every secret shared among the same
+// users, each of which then takes O(t).
+pub struct Interpolation {
+    ids: Vec<usize>,
+    coeffs: Vec<u64>,
+}
+
+impl Interpolation {
+    pub fn new(ids: &[usize]) -> Result<Self, AggregationError> {
+        let points = ids.iter().map(|id| evaluation_point(*id)).collect::<Result<Vec<u64>, _>>()?;
+        if points.is_empty() || points.iter().collect::<BTreeSet<_>>().len() != points.len() {
+            return Err(ErrorKind::ShareReconstruction.into())
+        }
+        let coeffs = points.iter().map(|x_j| {
+            let (num, den) = points.iter()
+                .filter(|x_m| *x_m != x_j)
+                .fold((1, 1), |(num, den), x_m| (mul(num, *x_m), mul(den, sub(*x_m, *x_j))));
+            mul(num, inv(den))
+        }).collect();
+        Ok(Interpolation { ids: ids.to_vec(), coeffs })
+    }
+
+    // Recovers a secret of `len` bytes from the shares of `ids`, given in
+    // the same order along with the id of the user who held them.
+    pub fn reconstruct(&self, shares: &[(usize, Share)], len: usize) -> Result<Vec<u8>, AggregationError> {
+        let chunks = len.div_ceil(CHUNK_BYTES);
+        for (id, s) in shares {
+            if s.point != evaluation_point(*id)? {
+                return Err(AggregationError::new(ErrorKind::MalformedMessage).for_user(*id))
+            }
+        }
+        if !shares.iter().map(|(id, _)| id).eq(self.ids.iter())
+            || shares.iter().any(|(_, s)| s.values.len() != chunks || s.values.iter().any(|y| *y >= PRIME))
+        {
+            return Err(ErrorKind::ShareReconstruction.into())
+        }
+
+        let mut secret = (0..chunks).flat_map(|k| {
+            let chunk = Iterator::zip(shares.iter(), self.coeffs.iter())
+                .fold(0, |acc, ((_, s), l)| add(acc, mul(s.values[k], *l)));
+            chunk.to_le_bytes().into_iter().take(CHUNK_BYTES)
+        }).collect::<Vec<u8>>();
+        secret.truncate(len);
+        Ok(secret)
+    }
+}
+
+// Recovers a secret of `len` bytes from (at least `threshold` of) its shares,
+// given along with the id of the user who held them.
+pub fn reconstruct(shares: &[(usize, Share)], len: usize) -> Result<Vec<u8>, AggregationError> {
+    for (id, s) in shares {
+        if s.point != evaluation_point(*id)? {
+            return Err(AggregationError::new(ErrorKind::MalformedMessage).for_user(*id))
+        }
+    }
+    let ids = shares.iter().map(|(id, _)| *id).collect::<Vec<usize>>();
+    Interpolation::new(&ids)?.reconstruct(shares, len)
+}
@@ -0,0 +1,79 @@
+use serde::{Serialize, Deserialize};
+use zeroize::Zeroizing;
+
+use crate::cohort::SessionId;
+use crate::sodium_bindings::*;
+use crate::error::*;
+
+// Snapshots of the state of a user, or of the whole user, sealed with
+// XChaCha20-Poly1305 so that they can be kept on disk between rounds: a
+// snapshot which has been tampered with, sealed under another key, or sealed
+// for another session or user, is refused.
+//
+// Every snapshot carries a counter, authenticated along with it, and a
+// snapshot is only restored if its counter is at least that of the last
+// snapshot taken or restored. Persisting that counter somewhere it cannot be
+// rolled back, and passing it to `User::with_snapshot_counter` when the user
+// is rebuilt, keeps an old snapshot from being restored in a newer session.
+
+pub enum SealingKey<'a> {
+    // A key held by the caller, e.g. in the keystore of the platform.
+    Key(&'a AeadKey),
+    // A passphrase, stretched with Argon2id under a random salt.
+    Passphrase(&'a [u8]),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    counter: u64,
+    salt: Option<Salt>,
+    nonce: AeadNonce,
+    c: Vec<u8>,
+}
+
+fn additional_data(counter: u64, session_id: &SessionId, id: usize, salt: &Option<Salt>) -> Vec<u8> {
+    let mut ad = b"user-state".to_vec();
+    ad.extend_from_slice(&counter.to_le_bytes());
+    ad.extend_from_slice(session_id);
+    ad.extend_from_slice(&(id as u64).to_le_bytes());
+    if let Some(salt) = salt {
+        ad.extend_from_slice(salt);
+    }
+    ad
+}
+
+pub fn seal(m: &[u8], counter: u64, session_id: &SessionId, id: usize, key: SealingKey) -> Result<Vec<u8>, AggregationError> {
+    let derived;
+    let (salt, k) = match key {
+        SealingKey::Key(k) => (None, k),
+        SealingKey::Passphrase(p) => {
+            let salt = gen_salt();
+            derived = derive_key(p, &salt)?;
+            (Some(salt), &derived)
+        },
+    };
+    let nonce = gen_aead_nonce();
+    let c = aead_wrap(m, &additional_data(counter, session_id, id, &salt), &nonce, k)?;
+    bincode::serialize(&Sealed { counter, salt, nonce, c }).map_err(|_| ErrorKind::Serialization.into())
+}
+
+// Opens a snapshot sealed for user `id` of `session_id`, provided it is not
+// older than `latest`, and returns its counter along with its content.
+pub fn open(blob: &[u8], latest: u64, session_id: &SessionId, id: usize, key: SealingKey) -> Result<(u64, Zeroizing<Vec<u8>>), AggregationError> {
+    let sealed: Sealed = bincode::deserialize(blob).map_err(|_| ErrorKind::MalformedMessage)?;
+    let derived;
+    let k = match (key, &sealed.salt) {
+        (SealingKey::Key(k), None) => k,
+        (SealingKey::Passphrase(p), Some(salt)) => {
+            derived = derive_key(p, salt)?;
+            &derived
+        },
+        _ => return Err(ErrorKind::DecryptionFailure.into()),
+    };
+    let ad = additional_data(sealed.counter, session_id, id, &sealed.salt);
+    let m = Zeroizing::new(aead_unwrap(&sealed.c, &ad, &sealed.nonce, k)?);
+    if sealed.counter < latest {
+        return Err(ErrorKind::StaleSnapshot { latest, received: sealed.counter }.into())
+    }
+    Ok((sealed.counter, m))
+}
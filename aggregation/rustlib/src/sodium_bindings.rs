@@ -3,6 +3,9 @@ use core::ffi::c_void;
 use libsodium_sys::*;
 use serde_big_array::big_array;
 
+use crate::error::*;
+use crate::secret::Secret;
+
 serde_big_array::big_array! { BigArray; }
 
 pub type Key = [u8; crypto_box_PUBLICKEYBYTES as usize];
@@ -13,8 +16,12 @@ pub type KXPublicKey = [u8; crypto_kx_PUBLICKEYBYTES as usize];
 pub type KXSecretKey = [u8; crypto_kx_SECRETKEYBYTES as usize];
 pub type KXSessionKey = [u8; crypto_kx_SESSIONKEYBYTES as usize];
 pub type SignPublicKey = [u8; crypto_sign_PUBLICKEYBYTES as usize];
-pub type SignSecretKey = [u8; crypto_sign_SECRETKEYBYTES as usize];
+pub type SignSecretKey = Secret<{ crypto_sign_SECRETKEYBYTES as usize }>;
 pub type Signature = [u8; crypto_sign_BYTES as usize];
+pub type Hash = [u8; crypto_generichash_BYTES as usize];
+pub type AeadKey = Secret<{ crypto_aead_xchacha20poly1305_ietf_KEYBYTES as usize }>;
+pub type AeadNonce = [u8; crypto_aead_xchacha20poly1305_ietf_NPUBBYTES as usize];
+pub type Salt = [u8; crypto_pwhash_SALTBYTES as usize];
 
 pub const SIGN_PUBLIC_KEY_BYTES: usize = crypto_sign_PUBLICKEYBYTES as usize;
 
@@ -35,22 +42,25 @@ pub fn gen_ae_keypair() -> (AEPublicKey, AESecretKey) {
     }
 }
 
-pub fn crypto_wrap(m: &[u8], nonce: Nonce, pk: AEPublicKey, sk: AESecretKey) -> Result<Vec<u8>, ()> {
+pub fn crypto_wrap(m: &[u8], nonce: Nonce, pk: AEPublicKey, sk: AESecretKey) -> Result<Vec<u8>, AggregationError> {
     let mut c = vec![0; (crypto_box_MACBYTES as usize) + m.len()];
     let res = unsafe {
         crypto_box_easy(c.as_mut_ptr(), m.as_ptr(), m.len() as u64, nonce.as_ptr(), pk.as_ptr(), sk.as_ptr())
     };
 
-    if res == 0 { Ok(c) } else { Err(()) }
+    if res == 0 { Ok(c) } else { Err(ErrorKind::EncryptionFailure.into()) }
 }
 
-pub fn crypto_unwrap(c: &[u8], nonce: Nonce, pk: AEPublicKey, sk: AESecretKey) -> Result<Vec<u8>, ()> {
+pub fn crypto_unwrap(c: &[u8], nonce: Nonce, pk: AEPublicKey, sk: AESecretKey) -> Result<Vec<u8>, AggregationError> {
+    if c.len() < crypto_box_MACBYTES as usize {
+        return Err(ErrorKind::DecryptionFailure.into())
+    }
     let mut m = vec![0; c.len() - (crypto_box_MACBYTES as usize)];
     let res = unsafe {
         crypto_box_open_easy(m.as_mut_ptr(), c.as_ptr(), c.len() as u64, nonce.as_ptr(), pk.as_ptr(), sk.as_ptr())
     };
 
-    if res == 0 { Ok(m) } else { Err(()) }
+    if res == 0 { Ok(m) } else { Err(ErrorKind::DecryptionFailure.into()) }
 }
 
 pub fn gen_key() -> Key {
@@ -69,22 +79,85 @@ pub fn gen_nonce() -> Nonce {
     nonce
 }
 
-pub fn crypto_secret_wrap(m: &[u8], nonce: Nonce, k: Key) -> Result<Vec<u8>, ()> {
+pub fn crypto_secret_wrap(m: &[u8], nonce: Nonce, k: &Key) -> Result<Vec<u8>, AggregationError> {
     let mut c = vec![0; (crypto_box_MACBYTES as usize) + m.len()];
     let res = unsafe {
         crypto_secretbox_easy(c.as_mut_ptr(), m.as_ptr(), m.len() as u64, nonce.as_ptr(), k.as_ptr())
     };
 
-    if res == 0 { Ok(c) } else { Err(()) }
+    if res == 0 { Ok(c) } else { Err(ErrorKind::EncryptionFailure.into()) }
 }
 
-pub fn crypto_secret_unwrap(c: &[u8], nonce: Nonce, k: Key) -> Result<Vec<u8>, ()> {
+pub fn crypto_secret_unwrap(c: &[u8], nonce: Nonce, k: &Key) -> Result<Vec<u8>, AggregationError> {
+    if c.len() < crypto_box_MACBYTES as usize {
+        return Err(ErrorKind::DecryptionFailure.into())
+    }
     let mut m = vec![0; c.len() - (crypto_box_MACBYTES as usize)];
     let res = unsafe {
         crypto_secretbox_open_easy(m.as_mut_ptr(), c.as_ptr(), c.len() as u64, nonce.as_ptr(), k.as_ptr())
     };
 
-    if res == 0 { Ok(m) } else { Err(()) }
+    if res == 0 { Ok(m) } else { Err(ErrorKind::DecryptionFailure.into()) }
+}
+
+pub fn gen_aead_key() -> AeadKey {
+    let mut k = AeadKey::zero();
+    unsafe {
+        crypto_aead_xchacha20poly1305_ietf_keygen(k.expose_mut().as_mut_ptr());
+    }
+    k
+}
+
+pub fn gen_aead_nonce() -> AeadNonce {
+    let mut nonce = [0; crypto_aead_xchacha20poly1305_ietf_NPUBBYTES as usize];
+    unsafe {
+        randombytes_buf(nonce.as_mut_ptr() as *mut c_void, nonce.len());
+    }
+    nonce
+}
+
+// XChaCha20-Poly1305, which authenticates `ad` along with `m`.
+pub fn aead_wrap(m: &[u8], ad: &[u8], nonce: &AeadNonce, k: &AeadKey) -> Result<Vec<u8>, AggregationError> {
+    let mut c = vec![0; m.len() + crypto_aead_xchacha20poly1305_ietf_ABYTES as usize];
+    let res = unsafe {
+        crypto_aead_xchacha20poly1305_ietf_encrypt(c.as_mut_ptr(), std::ptr::null_mut(), m.as_ptr(), m.len() as u64,
+            ad.as_ptr(), ad.len() as u64, std::ptr::null(), nonce.as_ptr(), k.expose().as_ptr())
+    };
+
+    if res == 0 { Ok(c) } else { Err(ErrorKind::EncryptionFailure.into()) }
+}
+
+pub fn aead_unwrap(c: &[u8], ad: &[u8], nonce: &AeadNonce, k: &AeadKey) -> Result<Vec<u8>, AggregationError> {
+    if c.len() < crypto_aead_xchacha20poly1305_ietf_ABYTES as usize {
+        return Err(ErrorKind::DecryptionFailure.into())
+    }
+    let mut m = vec![0; c.len() - crypto_aead_xchacha20poly1305_ietf_ABYTES as usize];
+    let res = unsafe {
+        crypto_aead_xchacha20poly1305_ietf_decrypt(m.as_mut_ptr(), std::ptr::null_mut(), std::ptr::null_mut(),
+            c.as_ptr(), c.len() as u64, ad.as_ptr(), ad.len() as u64, nonce.as_ptr(), k.expose().as_ptr())
+    };
+
+    if res == 0 { Ok(m) } else { Err(ErrorKind::DecryptionFailure.into()) }
+}
+
+pub fn gen_salt() -> Salt {
+    let mut salt = [0; crypto_pwhash_SALTBYTES as usize];
+    unsafe {
+        randombytes_buf(salt.as_mut_ptr() as *mut c_void, salt.len());
+    }
+    salt
+}
+
+// Stretches a passphrase into a key with Argon2id.
+pub fn derive_key(passphrase: &[u8], salt: &Salt) -> Result<AeadKey, AggregationError> {
+    let mut k = AeadKey::zero();
+    let res = unsafe {
+        crypto_pwhash(k.expose_mut().as_mut_ptr(), crypto_aead_xchacha20poly1305_ietf_KEYBYTES as u64,
+            passphrase.as_ptr() as *const _, passphrase.len() as u64, salt.as_ptr(),
+            crypto_pwhash_OPSLIMIT_INTERACTIVE as u64, crypto_pwhash_MEMLIMIT_INTERACTIVE as usize,
+            crypto_pwhash_ALG_DEFAULT as i32)
+    };
+    if res == 0 { Ok(k) } else { Err(ErrorKind::InvalidParameters.into()) }
 }
 
 pub fn gen_kx_keypair() -> (KXPublicKey, KXSecretKey) {
@@ -96,29 +169,37 @@ pub fn gen_kx_keypair() -> (KXPublicKey, KXSecretKey) {
     (pk, sk)
 }
 
-pub fn kx_client_keys(client_pk: KXPublicKey, client_sk: KXSecretKey, server_pk: KXPublicKey) -> Result<(KXSessionKey, KXSessionKey), ()> {
+pub fn kx_client_keys(client_pk: KXPublicKey, client_sk: KXSecretKey, server_pk: KXPublicKey) -> Result<(KXSessionKey, KXSessionKey), AggregationError> {
     let mut rx = [0; crypto_kx_SESSIONKEYBYTES as usize];
     let mut tx = [0; crypto_kx_SESSIONKEYBYTES as usize];
     let res = unsafe {
         crypto_kx_client_session_keys(rx.as_mut_ptr(), tx.as_mut_ptr(), client_pk.as_ptr(), client_sk.as_ptr(), server_pk.as_ptr())
     };
-    if res == 0 { Ok((rx, tx)) } else { Err(()) }
+    if res == 0 { Ok((rx, tx)) } else { Err(ErrorKind::MalformedMessage.into()) }
 }
 
-pub fn kx_server_keys(server_pk: KXPublicKey, server_sk: KXSecretKey, client_pk: KXPublicKey) -> Result<(KXSessionKey, KXSessionKey), ()> {
+pub fn kx_server_keys(server_pk: KXPublicKey, server_sk: KXSecretKey, client_pk: KXPublicKey) -> Result<(KXSessionKey, KXSessionKey), AggregationError> {
     let mut rx = [0; crypto_kx_SESSIONKEYBYTES as usize];
     let mut tx = [0; crypto_kx_SESSIONKEYBYTES as usize];
     let res = unsafe {
         crypto_kx_server_session_keys(rx.as_mut_ptr(), tx.as_mut_ptr(), server_pk.as_ptr(), server_sk.as_ptr(), client_pk.as_ptr())
     };
-    if res == 0 { Ok((rx, tx)) } else { Err(()) }
+    if res == 0 { Ok((rx, tx)) } else { Err(ErrorKind::MalformedMessage.into()) }
+}
+
+pub fn hash(m: &[u8]) -> Hash {
+    let mut h = [0; crypto_generichash_BYTES as usize];
+    unsafe {
+        crypto_generichash(h.as_mut_ptr(), h.len(), m.as_ptr(), m.len() as u64, std::ptr::null(), 0);
+    }
+    h
 }
 
 pub fn gen_sign_keypair() -> (SignPublicKey, SignSecretKey) {
     let mut pk = [0; crypto_sign_PUBLICKEYBYTES as usize];
-    let mut sk = [0; crypto_sign_SECRETKEYBYTES as usize];
+    let mut sk = SignSecretKey::zero();
     unsafe {
-        crypto_sign_keypair(pk.as_mut_ptr(), sk.as_mut_ptr());
+        crypto_sign_keypair(pk.as_mut_ptr(), sk.expose_mut().as_mut_ptr());
     };
     (pk, sk)
 }
@@ -126,15 +207,15 @@ pub fn gen_sign_keypair() -> (SignPublicKey, SignSecretKey) {
 pub fn sign(m: &[u8], sk: &SignSecretKey) -> Signature {
     let mut sig = [0; crypto_sign_BYTES as usize];
     unsafe {
-        crypto_sign_detached(sig.as_mut_ptr(), std::ptr::null_mut(), m.as_ptr(), m.len() as u64, sk.as_ptr());
+        crypto_sign_detached(sig.as_mut_ptr(), std::ptr::null_mut(), m.as_ptr(), m.len() as u64, sk.expose().as_ptr());
     }
     sig
 }
 
-pub fn verify_signature(m: &[u8], sig: &Signature, pk: &SignPublicKey) -> Result<(), ()> {
+pub fn verify_signature(m: &[u8], sig: &Signature, pk: &SignPublicKey) -> Result<(), AggregationError> {
     let res = unsafe {
         crypto_sign_verify_detached(sig.as_ptr(), m.as_ptr(), m.len() as u64, pk.as_ptr())
     };
-    if res == 0 { Ok(()) } else { Err(()) }
+    if res == 0 { Ok(()) } else { Err(ErrorKind::BadSignature.into()) }
 }
 
@@ -7,17 +7,40 @@ use serde::{Serialize, Deserialize};
 use serde_big_array::big_array;
 
 use crate::sodium_bindings::*;
+use crate::ring::{Ring, packed};
 use crate::helpers::*;
+use crate::dp::NoiseConfig;
+use crate::graph::Neighbourhoods;
+use crate::error::*;
 
 serde_big_array::big_array! { BigArray; }
 
-pub struct UserData {
+// Against an honest-but-curious server, the consistency check of Round 3 is
+// useless: users go straight from masked input collection to unmasking,
+// receiving `UserInput::Round3` and answering with `UserOutput::Round4`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub enum ThreatModel {
+    #[default]
+    Malicious,
+    SemiHonest,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct UserData<R: Ring = Wrapping<i64>> {
     pub id: usize,
     pub threshold: usize,
     pub sign_pk: SignPublicKey,
     pub sign_sk: SignSecretKey,
     pub others_sign_pks: Arc<BTreeMap<usize, SignPublicKey>>,
-    pub vec: Vec<Wrapping<i64>>,
+    pub vec_len: usize,
+    pub vec: Vec<R>,
+    pub weight: Option<R>,
+    pub noise: Option<NoiseConfig>,
+    pub sparse: bool,
+    pub iterations: usize,
+    pub threat_model: ThreatModel,
+    pub snapshot_counter: u64,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -34,6 +57,13 @@ pub struct OthersKeysData {
     pub rand_pks: BTreeMap<usize, KAPublicKey>,
 }
 
+// The iteration of a session in progress, and the users taking part in it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Iteration {
+    pub index: usize,
+    pub participants: BTreeSet<usize>,
+}
+
 // For reference on what each "round" is, see
 // *Practical Secure Aggregation for Privacy-Preserving Machine Learning*,
 // Bonowitz et. al. https://eprint.iacr.org/2017/281.pdf
@@ -42,11 +72,26 @@ pub struct OthersKeysData {
 pub enum UserState {
     Round0,
     Round1(OwnKeysData),
-    Round2(OwnKeysData, OthersKeysData, [u8; 32]),
-    Round3(OwnKeysData, OthersKeysData, [u8; 32], BTreeMap<usize, CryptoMsg>),
-    Round4(OwnKeysData, OthersKeysData, [u8; 32], BTreeMap<usize, CryptoMsg>, BTreeSet<usize>),
+    Round2(OwnKeysData, OthersKeysData, Vec<Seed>),
+    Round3(OwnKeysData, OthersKeysData, Vec<Seed>, BTreeMap<usize, CryptoMsg>, Iteration),
+    Round4(OwnKeysData, OthersKeysData, Vec<Seed>, BTreeMap<usize, CryptoMsg>, Iteration, BTreeSet<usize>),
+    // Between two iterations of a session, with the users alive in the last one.
+    Idle(OwnKeysData, OthersKeysData, Vec<Seed>, BTreeMap<usize, CryptoMsg>, Iteration, BTreeSet<usize>),
     Done,
-    Failed,
+    Failed(AggregationError),
+}
+
+impl UserState {
+    pub fn round(&self) -> Option<usize> {
+        match self {
+            UserState::Round0 => Some(0),
+            UserState::Round1(..) => Some(1),
+            UserState::Round2(..) => Some(2),
+            UserState::Round3(..) => Some(3),
+            UserState::Round4(..) => Some(4),
+            UserState::Idle(..) | UserState::Done | UserState::Failed(_) => None,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -56,36 +101,95 @@ pub enum UserInput {
     Round2(BTreeMap<usize, CryptoMsg>),
     Round3(Vec<usize>),
     Round4(BTreeMap<usize, BundledSignature>),
+    NextIteration(Vec<usize>),
+}
+
+impl UserInput {
+    // The round of the user it is meant for.
+    pub fn round(&self) -> Option<usize> {
+        match self {
+            UserInput::Round0() => Some(0),
+            UserInput::Round1(_) => Some(1),
+            UserInput::Round2(_) => Some(2),
+            UserInput::Round3(_) => Some(3),
+            UserInput::Round4(_) => Some(4),
+            UserInput::NextIteration(_) => None,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
-pub enum UserOutput {
+#[serde(bound = "")]
+pub enum UserOutput<R: Ring = Wrapping<i64>> {
     Round0(Signed<KAPublicKey>, Signed<KAPublicKey>),
     Round1(BTreeMap<usize, CryptoMsg>),
-    Round2(Vec<Wrapping<i64>>),
+    // Masked inputs only take `R::BITS` bits per element on the wire.
+    Round2(#[serde(with = "packed")] Vec<R>),
     Round3(BundledSignature),
     Round4(BTreeMap<usize, RevealedShare>),
 }
 
+impl<R: Ring> UserOutput<R> {
+    pub fn round(&self) -> usize {
+        match self {
+            UserOutput::Round0(..) => 0,
+            UserOutput::Round1(_) => 1,
+            UserOutput::Round2(_) => 2,
+            UserOutput::Round3(_) => 3,
+            UserOutput::Round4(_) => 4,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
-pub enum ServerState {
+#[serde(bound = "")]
+pub enum ServerState<R: Ring = Wrapping<i64>> {
     Round0(Collector<(Signed<KAPublicKey>, Signed<KAPublicKey>)>),
-    Round1(Collector<BTreeMap<usize, CryptoMsg>>, BTreeMap<usize, KAPublicKey>),
-    Round2(Collector<Vec<Wrapping<i64>>>, BTreeMap<usize, KAPublicKey>, BTreeSet<usize>),
-    Round3(Collector<BundledSignature>, BTreeMap<usize, KAPublicKey>, BTreeSet<usize>, Vec<Vec<Wrapping<i64>>>, BTreeSet<usize>),
-    Round4(Collector<BTreeMap<usize, RevealedShare>>, BTreeMap<usize, KAPublicKey>, BTreeSet<usize>, Vec<Vec<Wrapping<i64>>>, BTreeSet<usize>),
+    Round1(Collector<BTreeMap<usize, CryptoMsg>>, BTreeMap<usize, KAPublicKey>, Neighbourhoods),
+    Round2(Accumulator<R>, BTreeMap<usize, KAPublicKey>, Neighbourhoods, Iteration),
+    Round3(Collector<BundledSignature>, BTreeMap<usize, KAPublicKey>, Neighbourhoods, Iteration, Vec<R>, BTreeSet<usize>),
+    Round4(Collector<BTreeMap<usize, RevealedShare>>, BTreeMap<usize, KAPublicKey>, Neighbourhoods, Iteration, Vec<R>, BTreeSet<usize>),
+    // Between two iterations of a session, with the users of the next one.
+    Idle(BTreeMap<usize, KAPublicKey>, Neighbourhoods, Iteration),
     Done,
-    Failed,
+    Failed(AggregationError),
+}
+
+impl<R: Ring> ServerState<R> {
+    pub fn round(&self) -> Option<usize> {
+        match self {
+            ServerState::Round0(..) => Some(0),
+            ServerState::Round1(..) => Some(1),
+            ServerState::Round2(..) => Some(2),
+            ServerState::Round3(..) => Some(3),
+            ServerState::Round4(..) => Some(4),
+            ServerState::Idle(..) | ServerState::Done | ServerState::Failed(_) => None,
+        }
+    }
+}
+
+// The result of a session: the sum of the inputs of `users`, and in weighted
+// mode the sum of their weights, by which every input has been multiplied.
+// `equivocators` sent conflicting messages during the session: they were
+// excluded from it, unless the conflict was about their masked input.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct Aggregate<R: Ring = Wrapping<i64>> {
+    pub vec: Vec<R>,
+    pub weight: Option<R>,
+    pub users: BTreeSet<usize>,
+    pub equivocators: BTreeSet<usize>,
 }
 
 #[derive(Serialize, Deserialize)]
-pub enum ServerOutput {
+#[serde(bound = "")]
+pub enum ServerOutput<R: Ring = Wrapping<i64>> {
     Messages(BTreeMap<usize, UserInput>),
-    Vector(Vec<Wrapping<i64>>),
+    Aggregate(Aggregate<R>),
 }
 
-pub enum ServerOutputSerialized {
+pub enum ServerOutputSerialized<R: Ring = Wrapping<i64>> {
     Messages(BTreeMap<usize, Vec<u8>>),
-    Vector(Vec<Wrapping<i64>>),
+    Aggregate(Aggregate<R>),
 }
 
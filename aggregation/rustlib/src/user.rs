@@ -3,14 +3,24 @@ use std::sync::Arc;
 use std::num::Wrapping;
 use std::collections::{BTreeMap, BTreeSet};
 
+use rand::rngs::OsRng;
 use replace_with::*;
 use x25519_dalek;
-use sss_rs::wrapped_sharing::{Secret, share};
-use serde_json;
+use serde::{Serialize, Deserialize};
+use zeroize::Zeroizing;
 
 use crate::sodium_bindings::*;
+use crate::secret::Secret;
+use crate::ring::Ring;
 use crate::helpers::*;
+use crate::shamir;
+use crate::encoding::FixedPointEncoding;
+use crate::cohort::{Manifest, SessionId};
+use crate::snapshot::{self, SealingKey};
+use crate::format::{self, Kind};
+use crate::wire::{self, Header, Negotiation, Sender};
 use crate::types::*;
+use crate::error::*;
 
 // Implements the client side of *Practical Secure Aggregation
 // for Privacy-Preserving Machine Learning*, Bonowitz et. al.
@@ -19,14 +29,16 @@ use crate::types::*;
 // See this paper for the reference on what each round does.
 
 // AdvertiseKeys -- See Bonawitz et. al.
-fn round_0(data: &UserData) -> (OwnKeysData, (Signed<KAPublicKey>, Signed<KAPublicKey>)) {
+fn round_0<R: Ring>(data: &UserData<R>) -> (OwnKeysData, (Signed<KAPublicKey>, Signed<KAPublicKey>)) {
     let (comm_pk, comm_sk) = {
         let secret = x25519_dalek::StaticSecret::new(rand_core::OsRng);
-        (x25519_dalek::PublicKey::from(&secret).to_bytes(), secret.to_bytes())
+        let bytes = Zeroizing::new(secret.to_bytes());
+        (x25519_dalek::PublicKey::from(&secret).to_bytes(), Secret::copy_from(&bytes))
     };
     let (rand_pk, rand_sk) = {
         let secret = x25519_dalek::StaticSecret::new(rand_core::OsRng);
-        (x25519_dalek::PublicKey::from(&secret).to_bytes(), secret.to_bytes())
+        let bytes = Zeroizing::new(secret.to_bytes());
+        (x25519_dalek::PublicKey::from(&secret).to_bytes(), Secret::copy_from(&bytes))
     };
     let own_keys = OwnKeysData {
         comm_pk,
@@ -39,201 +51,548 @@ fn round_0(data: &UserData) -> (OwnKeysData, (Signed<KAPublicKey>, Signed<KAPubl
 }
 
 // ShareKeys -- See Bonawitz et. al.
-fn round_1(
-    data: &UserData,
+//
+// In a session of several iterations, a fresh seed is shared for each of
+// them, as the seed of an alive user is revealed at the end of an iteration.
+fn round_1<R: Ring>(
+    data: &UserData<R>,
     own_keys: OwnKeysData,
     v: BTreeMap<usize, (Signed<KAPublicKey>, Signed<KAPublicKey>)>
 )
-    -> Result<((OwnKeysData, OthersKeysData, [u8; 32]), BTreeMap<usize, CryptoMsg>), ()>
+    -> Result<((OwnKeysData, OthersKeysData, Vec<Seed>), BTreeMap<usize, CryptoMsg>), AggregationError>
 {
+    if data.iterations == 0 {
+        return Err(ErrorKind::InvalidParameters.into())
+    }
+
     let n = v.len();
     if n < data.threshold {
-        return Err(())
+        return Err(ErrorKind::BelowThreshold { threshold: data.threshold, received: n }.into())
     }
 
-    let i = v.iter()
-        .map(|(id, (x, y))| -> Option<_> { Some((data.others_sign_pks.get(id)?, (x, y))) })
-        .collect::<Option<Vec<_>>>()
-        .ok_or(())?;
-
-    if !(i.into_iter().all(|(pk, (x, y))| {
-        x.verify(pk).is_ok() && y.verify(pk).is_ok()
-    })) {
-        return Err(())
-    }
+    v.iter().try_for_each(|(id, (x, y))| {
+        let pk = data.others_sign_pks.get(id)
+            .ok_or_else(|| AggregationError::new(ErrorKind::UnknownUser).for_user(*id))?;
+        x.verify(pk).and(y.verify(pk)).map_err(|e| e.for_user(*id))
+    })?;
 
     let comm_pks: BTreeMap<usize, KAPublicKey> = v.iter().map(|(id, (x, _))| (*id, *x.msg())).collect();
     let rand_pks: BTreeMap<usize, KAPublicKey> = v.iter().map(|(id, (_, x))| (*id, *x.msg())).collect();
 
-    let seed = {
-        let mut seed = [0; 32];
-        getrandom::getrandom(&mut seed).map_err(|_| ())?;
-        seed
-    };
+    let seeds = (0..data.iterations).map(|_| Seed::random()).collect::<Result<Vec<Seed>, AggregationError>>()?;
 
-    //FIXME: Find an implementation that allows for higher numbers of shares !
-    let rand_sk_shares = share(Secret::InMemory(own_keys.rand_sk.to_vec()), data.threshold as u8, n as u8, true).map_err(|_| ())?;
-    let seed_shares = share(Secret::InMemory(seed.to_vec()), data.threshold as u8, n as u8, true).map_err(|_| ())?;
+    let ids: Vec<usize> = comm_pks.keys().cloned().collect();
+    let mut rand_sk_shares = shamir::share(own_keys.rand_sk.expose(), data.threshold, &ids)?;
+    let mut seed_shares = seeds.iter()
+        .map(|seed| shamir::share(seed.expose(), data.threshold, &ids))
+        .collect::<Result<Vec<_>, AggregationError>>()?;
 
     let msgs: BTreeMap<usize, CryptoMsg> = comm_pks.iter()
-        .zip(Iterator::zip(rand_sk_shares.into_iter(), seed_shares.into_iter()))
-        .map(|((id, other_comm_pk), (rand_sk_share, seed_share))| {
-            let common_key = x25519_dalek::x25519(own_keys.comm_sk, other_comm_pk.clone());
-            let msg_struct = MaskGenShares::new(data.id, *id, rand_sk_share, seed_share);
+        .map(|(id, other_comm_pk)| {
+            let rand_sk_share = rand_sk_shares.remove(id).ok_or(ErrorKind::InvalidParameters)?;
+            let seed_shares = seed_shares.iter_mut()
+                .map(|shares| shares.remove(id).ok_or(ErrorKind::InvalidParameters))
+                .collect::<Result<_, _>>()?;
+            let common_key = key_agreement(&own_keys.comm_sk, other_comm_pk);
+            let msg_struct = MaskGenShares::new(data.id, *id, rand_sk_share, seed_shares);
 
             let msg = CryptoMsg::new(
-                &bincode::serialize(&msg_struct).map_err(|_| ())?,
-                common_key)?;
+                &Zeroizing::new(bincode::serialize(&msg_struct).map_err(|_| ErrorKind::Serialization)?),
+                &common_key).map_err(|e| e.for_user(*id))?;
             Ok((*id, msg))
-        }).collect::<Result<_, ()>>()?;
+        }).collect::<Result<_, AggregationError>>()?;
 
     let others_keys = OthersKeysData { comm_pks, rand_pks };
     
-    Ok(((own_keys, others_keys, seed), msgs))
+    Ok(((own_keys, others_keys, seeds), msgs))
 }
 
 // MaskedInputCollection -- See Bonawitz et. al.
-fn round_2(
-    data: &UserData,
+fn round_2<R: Ring>(
+    data: &UserData<R>,
     own_keys: OwnKeysData,
     others_keys: OthersKeysData,
-    own_seed: [u8; 32],
-    crypted_keys: BTreeMap<usize, CryptoMsg>
+    seeds: Vec<Seed>,
+    crypted_keys: BTreeMap<usize, CryptoMsg>,
+    iteration: Iteration
 )
-    -> Result<((OwnKeysData, OthersKeysData, [u8; 32], BTreeMap<usize, CryptoMsg>), Vec<Wrapping<i64>>), ()>
+    -> Result<((OwnKeysData, OthersKeysData, Vec<Seed>, BTreeMap<usize, CryptoMsg>, Iteration), Vec<R>), AggregationError>
 {
-    let u_2: Vec<usize> = crypted_keys.keys().cloned().collect();
+    let u_2 = &iteration.participants;
 
     if u_2.len() < data.threshold {
-        return Err(())
+        return Err(ErrorKind::BelowThreshold { threshold: data.threshold, received: u_2.len() }.into())
     }
 
-    let other_masks: Vec<Vec<Wrapping<i64>>> = u_2.into_iter().map(|v| {
-        let rand_sk = own_keys.rand_sk;
-        let other_rand_pk = others_keys.rand_pks.get(&v).ok_or(())?;
-        let common_seed = x25519_dalek::x25519(rand_sk, other_rand_pk.clone());
+    let own_seed = seeds.get(iteration.index).ok_or(ErrorKind::UnexpectedRound)?;
 
-        use std::cmp::Ordering;
-        let l = match usize::cmp(&v, &data.id) {
-            Ordering::Less => 1,
-            Ordering::Equal => 0,
-            Ordering::Greater => -1,
-        };
-        Ok(scalar_mul(Wrapping(l), vector_from_seed(common_seed, data.vec.len())))
-    }).collect::<Result<_, ()>>()?;
-    let own_mask = vector_from_seed(own_seed.clone(), data.vec.len());
-    let sum: Vec<Wrapping<i64>> = sum_components(
-        Iterator::chain(std::iter::once(data.vec.clone()), std::iter::once(own_mask))
-            .chain(other_masks), data.vec.len());
-
-    Ok(((own_keys, others_keys, own_seed, crypted_keys), sum))
+    // In weighted mode, the weight is masked along with the weighted input.
+    let mut input: Vec<R> = match data.weight {
+        Some(w) => data.vec.iter().map(|x| w.mul(*x)).chain(std::iter::once(w)).collect(),
+        None => data.vec.clone(),
+    };
+
+    // The noise is added after weighting, so that the noise of the aggregate
+    // does not depend on the weights; the weight itself is left exact.
+    if let Some(noise) = &data.noise {
+        let noise = noise.sample(data.vec.len(), &mut OsRng);
+        input.iter_mut().zip(noise).for_each(|(x, e)| *x = x.add(R::from_i64(e)));
+    }
+
+    let pairwise_masks = u_2.iter().filter(|v| **v != data.id).map(|v| {
+        let other_rand_pk = others_keys.rand_pks.get(v)
+            .ok_or_else(|| AggregationError::new(ErrorKind::UnknownUser).for_user(*v))?;
+        let common_seed = iteration_seed(&key_agreement(&own_keys.rand_sk, other_rand_pk), iteration.index);
+        let sign = if *v < data.id { Sign::Plus } else { Sign::Minus };
+        Ok((sign, common_seed))
+    });
+    // The seeds are only copied into `masks`, which is wiped once applied.
+    let mut masks = std::iter::once(Ok((Sign::Plus, *own_seed.expose())))
+        .chain(pairwise_masks)
+        .collect::<Result<Vec<_>, AggregationError>>()?;
+    let mut sum = input;
+    apply_masks(&mut sum, &masks);
+    wipe_masks(&mut masks);
+
+    Ok(((own_keys, others_keys, seeds, crypted_keys, iteration), sum))
+}
+
+// The alive users sign, so that they all agree on who is alive in which iteration.
+fn alive_message(iteration: &Iteration, alive: &BTreeSet<usize>) -> Result<Vec<u8>, AggregationError> {
+    bincode::serialize(&(iteration.index, alive)).map_err(|_| ErrorKind::Serialization.into())
 }
 
 // ConsistencyCheck -- See Bonawitz et. al.
-fn round_3(
-    data: &UserData,
+fn round_3<R: Ring>(
+    data: &UserData<R>,
     own_keys: OwnKeysData,
     others_keys: OthersKeysData,
-    own_seed: [u8; 32],
+    seeds: Vec<Seed>,
     crypted_keys: BTreeMap<usize, CryptoMsg>,
+    iteration: Iteration,
     users: Vec<usize>
 )
-    -> Result<((OwnKeysData, OthersKeysData, [u8; 32], BTreeMap<usize, CryptoMsg>, BTreeSet<usize>), Signature), ()> {
-    if users.len() < 3 {
-        return Err(())
+    -> Result<((OwnKeysData, OthersKeysData, Vec<Seed>, BTreeMap<usize, CryptoMsg>, Iteration, BTreeSet<usize>), Signature), AggregationError> {
+    if users.len() < data.threshold {
+        return Err(ErrorKind::BelowThreshold { threshold: data.threshold, received: users.len() }.into())
     }
 
     let alive: BTreeSet<usize> = users.into_iter().collect();
+    // In SecAgg+ mode, we only know about our neighbours, see `reveal_shares`.
+    if !data.sparse {
+        if let Some(v) = alive.difference(&iteration.participants).next() {
+            return Err(AggregationError::new(ErrorKind::UnknownUser).for_user(*v))
+        }
+    }
+    let alive_msg = alive_message(&iteration, &alive)?;
 
-    Ok(((own_keys, others_keys, own_seed, crypted_keys, alive.clone()), sign(&bincode::serialize(&alive).map_err(|_| ())?, &data.sign_sk)))
+    Ok(((own_keys, others_keys, seeds, crypted_keys, iteration, alive), sign(&alive_msg, &data.sign_sk)))
 }
 
 // Unmasking -- See Bonawitz et. al.
-fn round_4(
-    data: &UserData,
+#[allow(clippy::too_many_arguments)]
+fn round_4<R: Ring>(
+    data: &UserData<R>,
     own_keys: OwnKeysData,
     others_keys: OthersKeysData,
-    _own_seed: [u8; 32],
+    seeds: Vec<Seed>,
     crypted_keys: BTreeMap<usize, CryptoMsg>,
+    iteration: Iteration,
     alive: BTreeSet<usize>,
     signatures: BTreeMap<usize, BundledSignature>
-) -> Result<((), BTreeMap<usize, RevealedShare>), ()> {
-    let u_2: BTreeSet<usize> = crypted_keys.keys().cloned().collect();
+)
+    -> Result<((OwnKeysData, OthersKeysData, Vec<Seed>, BTreeMap<usize, CryptoMsg>, Iteration, BTreeSet<usize>), BTreeMap<usize, RevealedShare>), AggregationError>
+{
     let u_4: BTreeSet<usize> = signatures.keys().cloned().collect();
 
     if u_4.len() < data.threshold {
-        return Err(())
+        return Err(ErrorKind::BelowThreshold { threshold: data.threshold, received: u_4.len() }.into())
     }
 
-    let sigs = signatures.into_iter()
-        .map(|(v, sig)| { Some((data.others_sign_pks.get(&v)?, sig)) })
-        .collect::<Option<Vec<_>>>()
-        .ok_or(())?;
-    let alive_msg = bincode::serialize(&alive).map_err(|_| ())?;
+    let alive_msg = alive_message(&iteration, &alive)?;
 
-    let signatures_ok = sigs.into_iter().all(|(other_sign_pk, sig)| {
-        verify_signature(&alive_msg, &sig.sig, other_sign_pk).is_ok()
-    });
+    signatures.iter().try_for_each(|(v, sig)| {
+        let other_sign_pk = data.others_sign_pks.get(v)
+            .ok_or_else(|| AggregationError::new(ErrorKind::UnknownUser).for_user(*v))?;
+        verify_signature(&alive_msg, &sig.sig, other_sign_pk).map_err(|e| e.for_user(*v))
+    })?;
 
-    if !signatures_ok {
-        return Err(())
-    }
+    reveal_shares(data, own_keys, others_keys, seeds, crypted_keys, iteration, alive)
+}
 
-    let dropped: BTreeSet<usize> = BTreeSet::difference(&u_2, &alive).cloned().collect();
+// Reveals the seed shares of the alive users and the key shares of the others.
+fn reveal_shares<R: Ring>(
+    data: &UserData<R>,
+    own_keys: OwnKeysData,
+    others_keys: OthersKeysData,
+    seeds: Vec<Seed>,
+    crypted_keys: BTreeMap<usize, CryptoMsg>,
+    iteration: Iteration,
+    alive: BTreeSet<usize>
+)
+    -> Result<((OwnKeysData, OthersKeysData, Vec<Seed>, BTreeMap<usize, CryptoMsg>, Iteration, BTreeSet<usize>), BTreeMap<usize, RevealedShare>), AggregationError>
+{
+    let u_2 = &iteration.participants;
+
+    // In SecAgg+ mode, the alive users are only known to us if they are our
+    // neighbours, and enough of them must be alive to unmask our input.
+    // Otherwise, the alive users must all take part in this iteration.
+    let alive: BTreeSet<usize> = if data.sparse {
+        let alive: BTreeSet<usize> = alive.intersection(u_2).cloned().collect();
+        if alive.len() < data.threshold {
+            return Err(ErrorKind::BelowThreshold { threshold: data.threshold, received: alive.len() }.into())
+        }
+        alive
+    } else if let Some(v) = alive.difference(u_2).next() {
+        return Err(AggregationError::new(ErrorKind::UnknownUser).for_user(*v))
+    } else {
+        alive
+    };
+    let dropped: BTreeSet<usize> = BTreeSet::difference(u_2, &alive).cloned().collect();
+    // After the first iteration, the seeds of all the participants have been
+    // revealed already, and with the key shares of one who dropped, its
+    // masked input in that earlier iteration would be unmasked alone.
+    if iteration.index > 0 && !dropped.is_empty() {
+        return Err(ErrorKind::BelowThreshold { threshold: u_2.len(), received: u_2.len() - dropped.len() }.into())
+    }
 
-    let gen_shares: BTreeMap<usize, MaskGenShares> = crypted_keys.into_iter()
+    let own_point = shamir::evaluation_point(data.id)?;
+    let gen_shares: BTreeMap<usize, MaskGenShares> = crypted_keys.iter()
+        .filter(|(v, _)| u_2.contains(v))
         .map(|(v, m)| {
-            let v_comm_pk = others_keys.comm_pks.get(&v).ok_or(())?;
-            let comm_sk = own_keys.comm_sk;
-            let clear_m = m.unwrap(x25519_dalek::x25519(comm_sk, v_comm_pk.clone()));
-            let share: MaskGenShares = bincode::deserialize(&clear_m.map_err(|_| ())?).map_err(|_| ())?;
-
-            if !(share.u == v && share.v == data.id) {
-                Err(())
+            let v_comm_pk = others_keys.comm_pks.get(v)
+                .ok_or_else(|| AggregationError::new(ErrorKind::UnknownUser).for_user(*v))?;
+            let clear_m = m.unwrap(&key_agreement(&own_keys.comm_sk, v_comm_pk)).map_err(|e| e.for_user(*v))?;
+            let share: MaskGenShares = bincode::deserialize(&clear_m)
+                .map_err(|_| AggregationError::new(ErrorKind::MalformedMessage).for_user(*v))?;
+
+            // All the shares must be evaluated at our own point.
+            if !(share.u == *v && share.v == data.id
+                && share.rand_sk_share.point == own_point
+                && share.seed_shares.len() == seeds.len()
+                && share.seed_shares.iter().all(|s| s.point == own_point)) {
+                Err(AggregationError::new(ErrorKind::MalformedMessage).for_user(*v))
             } else {
-                Ok((v, share))
+                Ok((*v, share))
             }
-        }).collect::<Result<_, ()>>()?;
+        }).collect::<Result<_, AggregationError>>()?;
 
+    let get_shares = |v: &usize| gen_shares.get(v)
+        .ok_or_else(|| AggregationError::new(ErrorKind::UnknownUser).for_user(*v));
     let revealed: BTreeMap<usize, RevealedShare> = Iterator::chain(
-        alive.iter().map(|v| Ok((*v, RevealedShare::Seed(gen_shares.get(&v).ok_or(())?.seed_share.clone())))),
-        dropped.iter().map(|v| Ok((*v, RevealedShare::RandSk(gen_shares.get(&v).ok_or(())?.rand_sk_share.clone()))))
-    ).collect::<Result<_, ()>>()?;
+        alive.iter().map(|v| Ok((*v, RevealedShare::Seed(get_shares(v)?.seed_shares[iteration.index].clone())))),
+        dropped.iter().map(|v| Ok((*v, RevealedShare::RandSk(get_shares(v)?.rand_sk_share.clone()))))
+    ).collect::<Result<_, AggregationError>>()?;
+
+    Ok(((own_keys, others_keys, seeds, crypted_keys, iteration, alive), revealed))
+}
+
+// Unmasking, right after MaskedInputCollection against a semi-honest server:
+// the users we are told are alive are taken at the server's word.
+fn round_3_semi_honest<R: Ring>(
+    data: &UserData<R>,
+    own_keys: OwnKeysData,
+    others_keys: OthersKeysData,
+    seeds: Vec<Seed>,
+    crypted_keys: BTreeMap<usize, CryptoMsg>,
+    iteration: Iteration,
+    users: Vec<usize>
+)
+    -> Result<((OwnKeysData, OthersKeysData, Vec<Seed>, BTreeMap<usize, CryptoMsg>, Iteration, BTreeSet<usize>), BTreeMap<usize, RevealedShare>), AggregationError>
+{
+    if users.len() < data.threshold {
+        return Err(ErrorKind::BelowThreshold { threshold: data.threshold, received: users.len() }.into())
+    }
+
+    let alive: BTreeSet<usize> = users.into_iter().collect();
+    reveal_shares(data, own_keys, others_keys, seeds, crypted_keys, iteration, alive)
+}
+
+// Starts the next iteration of a session with the given users, who must all
+// have been alive in the last one: the keys of the others may have been
+// revealed, so that their masks would not protect anything anymore.
+#[allow(clippy::too_many_arguments)]
+fn round_next<R: Ring>(
+    data: &UserData<R>,
+    own_keys: OwnKeysData,
+    others_keys: OthersKeysData,
+    seeds: Vec<Seed>,
+    crypted_keys: BTreeMap<usize, CryptoMsg>,
+    iteration: Iteration,
+    alive: BTreeSet<usize>,
+    users: Vec<usize>
+)
+    -> Result<((OwnKeysData, OthersKeysData, Vec<Seed>, BTreeMap<usize, CryptoMsg>, Iteration), Vec<R>), AggregationError>
+{
+    // In SecAgg+ mode, we only take our neighbours into account.
+    let participants: BTreeSet<usize> = users.into_iter().filter(|v| crypted_keys.contains_key(v)).collect();
+    if let Some(v) = participants.difference(&alive).next() {
+        return Err(AggregationError::new(ErrorKind::MalformedMessage).for_user(*v))
+    }
+    if !participants.contains(&data.id) {
+        return Err(ErrorKind::MalformedMessage.into())
+    }
+
+    let iteration = Iteration { index: iteration.index + 1, participants };
+    round_2(data, own_keys, others_keys, seeds, crypted_keys, iteration)
+}
+
+fn end_of_iteration(
+    own_keys: OwnKeysData,
+    others_keys: OthersKeysData,
+    seeds: Vec<Seed>,
+    crypted_keys: BTreeMap<usize, CryptoMsg>,
+    iteration: Iteration,
+    alive: BTreeSet<usize>
+) -> UserState {
+    if iteration.index + 1 < seeds.len() {
+        UserState::Idle(own_keys, others_keys, seeds, crypted_keys, iteration, alive)
+    } else {
+        UserState::Done
+    }
+}
 
-    Ok(((), revealed))
+fn fail_user<T>(e: AggregationError) -> (Result<T, AggregationError>, UserState) {
+    (Err(e.clone()), UserState::Failed(e))
 }
 
-pub struct User {
-    data: UserData,
+fn check_len<R: Ring>(id: usize, vec_len: usize, vec: &[R]) -> Result<(), AggregationError> {
+    if vec.len() == vec_len {
+        Ok(())
+    } else {
+        Err(AggregationError::new(ErrorKind::LengthMismatch { expected: vec_len, received: vec.len() }).for_user(id))
+    }
+}
+
+// Generic so as to be written from references and read into owned values.
+#[derive(Serialize, Deserialize)]
+struct UserSnapshot<D, S> {
+    data: D,
+    session_id: SessionId,
+    #[serde(default, skip_serializing_if = "Negotiation::is_default")]
+    wire: Negotiation,
+    state: S,
+}
+
+pub struct User<R: Ring = Wrapping<i64>> {
+    data: UserData<R>,
+    session_id: SessionId,
+    wire: Negotiation,
     state: UserState,
 }
 
-impl User {
+impl<R: Ring> User<R> {
+    // `vec` must have the `vec_len` elements agreed on for the session.
+    // The inputs of the session, once encoded with `encoding`, must sum
+    // without overflowing the ring; the user adds the noise of the encoding
+    // to its input, if any, see `dp::NoiseConfig`.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: usize,
         threshold: usize,
+        vec_len: usize,
         sign_pk: SignPublicKey,
         sign_sk: SignSecretKey,
-        vec: Vec<Wrapping<i64>>,
-        others_sign_pks: Arc<BTreeMap<usize, SignPublicKey>>
-    ) -> User {
-        User {
+        vec: Vec<R>,
+        others_sign_pks: Arc<BTreeMap<usize, SignPublicKey>>,
+        encoding: &FixedPointEncoding<R>,
+    ) -> Result<Self, AggregationError> {
+        check_len(id, vec_len, &vec)?;
+        encoding.check_contributors(others_sign_pks.len())?;
+        Ok(User {
             data: UserData {
                 id, threshold,
                 sign_pk, sign_sk,
-                vec,
+                vec_len, vec,
+                weight: None,
+                noise: encoding.noise().cloned(),
+                sparse: false,
+                iterations: 1,
+                threat_model: ThreatModel::Malicious,
+                snapshot_counter: 0,
                 others_sign_pks,
             },
+            session_id: SessionId::default(),
+            wire: Negotiation::default(),
             state: UserState::Round0,
+        })
+    }
+
+    // Joins the session of a manifest published by a `cohort::Registrar`,
+    // once checked that it is the one we registered for.
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_manifest(
+        id: usize,
+        sign_pk: SignPublicKey,
+        sign_sk: SignSecretKey,
+        vec: Vec<R>,
+        manifest: Signed<Manifest>,
+        coordinator_pk: &SignPublicKey,
+        session_id: &SessionId,
+        encoding: &FixedPointEncoding<R>,
+    ) -> Result<Self, AggregationError> {
+        let manifest = Manifest::verify(manifest, coordinator_pk, session_id, id, &sign_pk)?;
+        let user = User::new(id, manifest.threshold, manifest.vec_len, sign_pk, sign_sk, vec, Arc::new(manifest.members), encoding)?;
+        Ok(user.with_session_id(manifest.session_id))
+    }
+
+    // Takes part in a weighted session, see `Server::new_weighted`, with a
+    // weight of at most the `max_weight` of the encoding.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_weighted(
+        id: usize,
+        threshold: usize,
+        vec_len: usize,
+        sign_pk: SignPublicKey,
+        sign_sk: SignSecretKey,
+        vec: Vec<R>,
+        weight: R,
+        others_sign_pks: Arc<BTreeMap<usize, SignPublicKey>>,
+        encoding: &FixedPointEncoding<R>,
+    ) -> Result<Self, AggregationError> {
+        if weight.to_u64() > encoding.max_weight() {
+            return Err(AggregationError::new(ErrorKind::InvalidParameters).for_user(id))
+        }
+        let mut user = User::new(id, threshold, vec_len, sign_pk, sign_sk, vec, others_sign_pks, encoding)?;
+        user.data.weight = Some(weight);
+        Ok(user)
+    }
+
+    // Takes part in a SecAgg+ session, see `Server::with_sparse_graph`:
+    // keys are only exchanged with the neighbours the server assigns us.
+    pub fn with_sparse_graph(mut self) -> Self {
+        self.data.sparse = true;
+        self
+    }
+
+    // Takes part in a session of several iterations, see
+    // `Server::with_iterations`, giving a new input with `set_input` before
+    // each iteration but the first.
+    pub fn with_iterations(mut self, iterations: usize) -> Self {
+        self.data.iterations = iterations;
+        self
+    }
+
+    // Skips the consistency check, see `Server::with_threat_model`.
+    pub fn with_threat_model(mut self, threat_model: ThreatModel) -> Self {
+        self.data.threat_model = threat_model;
+        self
+    }
+
+    // Which session the frames of `round_serialized` belong to.
+    pub fn with_session_id(mut self, session_id: SessionId) -> Self {
+        self.session_id = session_id;
+        self
+    }
+
+    pub fn session_id(&self) -> &SessionId {
+        &self.session_id
+    }
+
+    // Also reads frames of the versions before `wire::WIRE_VERSION`, for a
+    // server which writes them.
+    pub fn with_legacy_wire(mut self) -> Self {
+        self.wire.legacy = true;
+        self
+    }
+
+    pub fn set_input(&mut self, vec: Vec<R>) -> Result<(), AggregationError> {
+        check_len(self.data.id, self.data.vec_len, &vec)?;
+        self.data.vec = vec;
+        Ok(())
+    }
+
+    // The counter of the last snapshot taken by this user before it was
+    // rebuilt, to refuse older ones; see `snapshot`.
+    pub fn with_snapshot_counter(mut self, counter: u64) -> Self {
+        self.data.snapshot_counter = counter;
+        self
+    }
+
+    pub fn snapshot_counter(&self) -> u64 {
+        self.data.snapshot_counter
+    }
+
+    // Seals the state under `key`, for this session and user only, with a
+    // higher counter than any snapshot taken or restored so far.
+    pub fn seal_state(&mut self, key: SealingKey) -> Result<Vec<u8>, AggregationError> {
+        let state = Zeroizing::new(self.serialize_state()?.into_bytes());
+        let counter = self.data.snapshot_counter + 1;
+        let blob = snapshot::seal(&state, counter, &self.session_id, self.data.id, key)?;
+        self.data.snapshot_counter = counter;
+        Ok(blob)
+    }
+
+    pub fn open_state(&mut self, blob: &[u8], key: SealingKey) -> Result<(), AggregationError> {
+        let (counter, state) = snapshot::open(blob, self.data.snapshot_counter, &self.session_id, self.data.id, key)?;
+        self.state = format::unwrap(Kind::UserState, &state)?;
+        self.data.snapshot_counter = counter;
+        Ok(())
+    }
+
+    // The whole user, configuration and keys included, to be restored with
+    // `from_snapshot`. The secret keys are in cleartext, so this is not safe
+    // to persist as is: use `seal_snapshot` to keep it on disk.
+    pub fn snapshot(&self) -> Result<Vec<u8>, AggregationError> {
+        let snapshot = UserSnapshot { data: &self.data, session_id: self.session_id, wire: self.wire, state: &self.state };
+        format::wrap(Kind::UserSnapshot, &snapshot).map(String::into_bytes)
+    }
+
+    // Fails on a snapshot whose configuration is not consistent, rather
+    // than resuming a session which could only go wrong.
+    pub fn from_snapshot(bytes: &[u8]) -> Result<Self, AggregationError> {
+        let UserSnapshot { data, session_id, wire, state }: UserSnapshot<UserData<R>, UserState> = format::unwrap(Kind::UserSnapshot, bytes)?;
+        check_len(data.id, data.vec_len, &data.vec)?;
+        // A secret key of libsodium ends with its public key.
+        if data.others_sign_pks.get(&data.id) != Some(&data.sign_pk) || data.sign_sk.expose()[32..] != data.sign_pk {
+            return Err(AggregationError::new(ErrorKind::InvalidParameters).for_user(data.id))
         }
+        let seeds = match &state {
+            UserState::Round2(_, _, seeds)
+                | UserState::Round3(_, _, seeds, ..)
+                | UserState::Round4(_, _, seeds, ..)
+                | UserState::Idle(_, _, seeds, ..) => Some(seeds.len()),
+            _ => None,
+        };
+        if seeds.is_some_and(|n| n != data.iterations) {
+            return Err(AggregationError::new(ErrorKind::InvalidParameters).for_user(data.id))
+        }
+        Ok(User { data, session_id, wire, state })
+    }
+
+    // Seals the whole user under `key`, like `seal_state`, to be restored with
+    // `open_snapshot` e.g. after a crash.
+    pub fn seal_snapshot(&mut self, key: SealingKey) -> Result<Vec<u8>, AggregationError> {
+        let snapshot = Zeroizing::new(self.snapshot()?);
+        let counter = self.data.snapshot_counter + 1;
+        let blob = snapshot::seal(&snapshot, counter, &self.session_id, self.data.id, key)?;
+        self.data.snapshot_counter = counter;
+        Ok(blob)
     }
 
-    pub fn serialize_state(&self) -> Result<String, ()> {
-        serde_json::to_string(&self.state).map_err(|_| ())
+    // Rebuilds user `id` of `session_id` from a snapshot sealed under `key`,
+    // provided its counter is at least `latest`; see `with_snapshot_counter`.
+    pub fn open_snapshot(
+        blob: &[u8],
+        session_id: &SessionId,
+        id: usize,
+        latest: u64,
+        key: SealingKey,
+    ) -> Result<Self, AggregationError> {
+        let (counter, snapshot) = snapshot::open(blob, latest, session_id, id, key)?;
+        let mut user = Self::from_snapshot(&snapshot)?;
+        user.data.snapshot_counter = counter;
+        Ok(user)
+    }
+
+    // The state in cleartext, secrets included; prefer `seal_state` to
+    // store it anywhere.
+    pub fn serialize_state(&self) -> Result<String, AggregationError> {
+        format::wrap(Kind::UserState, &self.state)
     }
 
-    pub fn recover_state(&mut self, s: &str) -> Result<(), ()> {
-        self.state = serde_json::from_str(s).map_err(|_| ())?;
+    pub fn recover_state(&mut self, s: &str) -> Result<(), AggregationError> {
+        self.state = format::unwrap(Kind::UserState, s.as_bytes())?;
         Ok(())
     }
 
@@ -241,18 +600,25 @@ impl User {
         self.data.id
     }
 
-    pub fn round_serialized(&mut self, input: &[u8]) -> Result<Vec<u8>, ()> {
-        match bincode::deserialize::<UserInput>(input) {
-            Ok(input) => match self.round(input) {
-                Ok(res) => bincode::serialize(&res).map_err(|_| ()),
-                Err(()) => Err(())
-            },
-            Err(_) => Err(())
-        }
+    // Takes a frame from the server, see `wire`, and answers with another.
+    // A frame for another session or round is refused without failing.
+    // The version of the first frame is the only one read afterwards.
+    pub fn round_serialized(&mut self, input: &[u8]) -> Result<Vec<u8>, AggregationError> {
+        let round = self.state.round();
+        let (header, input) = wire::decode(input, &self.session_id, round, Sender::Server, &self.wire)
+            .map_err(|e| e.at_round(round))?;
+        self.wire.pin(header.version);
+        let output = self.round(input)?;
+        let header = Header {
+            version: header.version,
+            ..Header::new(self.session_id, Some(output.round()), Sender::User(self.data.id))
+        };
+        wire::encode(&header, &output)
     }
 
-    pub fn round(&mut self, input: UserInput) -> Result<UserOutput, ()> {
+    pub fn round(&mut self, input: UserInput) -> Result<UserOutput<R>, AggregationError> {
         replace_with_or_abort_and_return(&mut self.state, |state| { // HACK
+            let round = state.round();
             match (state, input) {
                 (UserState::Round0, UserInput::Round0()) => {
                     let (own_keys, (comm_pk, rand_pk)) =
@@ -265,34 +631,54 @@ impl User {
                         Ok(((own_keys, others_keys, seed), msgs)) =>
                             (Ok(UserOutput::Round1(msgs)),
                                 UserState::Round2(own_keys, others_keys, seed)),
-                        Err(_) => (Err(()), UserState::Failed)
+                        Err(e) => fail_user(e.at_round(round))
                     }
                 },
-                (UserState::Round2(own_keys, others_keys, own_seed), UserInput::Round2(crypted_keys)) => {
-                    match round_2(&self.data, own_keys, others_keys, own_seed, crypted_keys) {
-                        Ok(((own_keys, others_keys, own_seed, crypted_keys), sum)) =>
+                (UserState::Round2(own_keys, others_keys, seeds), UserInput::Round2(crypted_keys)) => {
+                    let iteration = Iteration { index: 0, participants: crypted_keys.keys().cloned().collect() };
+                    match round_2(&self.data, own_keys, others_keys, seeds, crypted_keys, iteration) {
+                        Ok(((own_keys, others_keys, seeds, crypted_keys, iteration), sum)) =>
                     (Ok(UserOutput::Round2(sum)),
-                        UserState::Round3(own_keys, others_keys, own_seed, crypted_keys)),
-                        Err(_) => (Err(()), UserState::Failed)
+                        UserState::Round3(own_keys, others_keys, seeds, crypted_keys, iteration)),
+                        Err(e) => fail_user(e.at_round(round))
                     }
                 },
-                (UserState::Round3(own_keys, others_keys, own_seed, crypted_keys), UserInput::Round3(users)) => {
-                    match round_3(&self.data, own_keys, others_keys, own_seed, crypted_keys, users) {
-                        Ok(((own_keys, others_keys, own_seed, crypted_keys, alive), sig)) =>
+                (UserState::Round3(own_keys, others_keys, seeds, crypted_keys, iteration), UserInput::Round3(users))
+                    if self.data.threat_model == ThreatModel::SemiHonest => {
+                    match round_3_semi_honest(&self.data, own_keys, others_keys, seeds, crypted_keys, iteration, users) {
+                        Ok(((own_keys, others_keys, seeds, crypted_keys, iteration, alive), x)) =>
+                            (Ok(UserOutput::Round4(x)),
+                                end_of_iteration(own_keys, others_keys, seeds, crypted_keys, iteration, alive)),
+                        Err(e) => fail_user(e.at_round(round))
+                    }
+                },
+                (UserState::Round3(own_keys, others_keys, seeds, crypted_keys, iteration), UserInput::Round3(users)) => {
+                    match round_3(&self.data, own_keys, others_keys, seeds, crypted_keys, iteration, users) {
+                        Ok(((own_keys, others_keys, seeds, crypted_keys, iteration, alive), sig)) =>
                             (Ok(UserOutput::Round3(BundledSignature::new(sig))),
-                                UserState::Round4(own_keys, others_keys, own_seed, crypted_keys, alive)),
-                        Err(_) => (Err(()), UserState::Failed)
+                                UserState::Round4(own_keys, others_keys, seeds, crypted_keys, iteration, alive)),
+                        Err(e) => fail_user(e.at_round(round))
                     }
                 },
-                (UserState::Round4(own_keys, others_keys, own_seed, crypted_keys, alive), UserInput::Round4(signatures)) => {
-                    match round_4(&self.data, own_keys, others_keys, own_seed, crypted_keys, alive, signatures) {
-                        Ok(((), x)) =>
+                (UserState::Round4(own_keys, others_keys, seeds, crypted_keys, iteration, alive), UserInput::Round4(signatures)) => {
+                    match round_4(&self.data, own_keys, others_keys, seeds, crypted_keys, iteration, alive, signatures) {
+                        Ok(((own_keys, others_keys, seeds, crypted_keys, iteration, alive), x)) =>
                             (Ok(UserOutput::Round4(x)),
-                                UserState::Done),
-                        Err(_) => (Err(()), UserState::Failed)
+                                end_of_iteration(own_keys, others_keys, seeds, crypted_keys, iteration, alive)),
+                        Err(e) => fail_user(e.at_round(round))
+                    }
+                },
+                (UserState::Idle(own_keys, others_keys, seeds, crypted_keys, iteration, alive), UserInput::NextIteration(users)) => {
+                    match round_next(&self.data, own_keys, others_keys, seeds, crypted_keys, iteration, alive, users) {
+                        Ok(((own_keys, others_keys, seeds, crypted_keys, iteration), sum)) =>
+                            (Ok(UserOutput::Round2(sum)),
+                                UserState::Round3(own_keys, others_keys, seeds, crypted_keys, iteration)),
+                        Err(e) => fail_user(e.at_round(round))
                     }
                 },
-                _ => (Err(()), UserState::Failed)
+                // Once failed, keep the error that caused the failure.
+                (UserState::Failed(e), _) => (Err(e.clone()), UserState::Failed(e)),
+                _ => fail_user(AggregationError::new(ErrorKind::UnexpectedRound).at_round(round))
             }
         })
     }
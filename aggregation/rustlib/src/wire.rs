@@ -0,0 +1,174 @@
+use std::ops::RangeInclusive;
+
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+
+use crate::cohort::SessionId;
+use crate::cbor::{self, Cbor};
+use crate::error::*;
+
+// Every message of the protocol travels in a frame, which tells which
+// session, round and sender it belongs to, so that a message sent to the
+// wrong place is refused before its payload is even decoded:
+//
+//   magic      4 bytes   "MZSA"
+//   version    u32       of the frame, little-endian
+//   session    16 bytes  see `cohort::SessionId`
+//   round      u8        the round the receiver is in, 0xff between iterations
+//   sender     u64       the id of a user, or 0xff..ff for the server
+//   length     u32       of the payload
+//   payload    `length` bytes, a `UserInput` or a `UserOutput`
+//
+// The version comes right after the magic number and is checked first, so
+// that a later version may change anything after it. We write
+// `WIRE_VERSION`, except users who answer the server in the version it wrote
+// to them in, and read versions from a peer as its `Negotiation` allows.
+//
+// Versions:
+// 1. Payloads in bincode, which only Rust reads.
+// 2. Payloads in CBOR, see `cbor`.
+
+pub const MAGIC: [u8; 4] = *b"MZSA";
+pub const WIRE_VERSION: u32 = 2;
+pub const MIN_WIRE_VERSION: u32 = 1;
+pub const HEADER_LEN: usize = 4 + 4 + 16 + 1 + 8 + 4;
+
+const NO_ROUND: u8 = u8::MAX;
+const SERVER_ID: u64 = u64::MAX;
+
+// Older versions are only read when explicitly allowed, and the version of
+// the first frame read from a peer is the only one read from it afterwards,
+// so that a session cannot be downgraded halfway through.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Negotiation {
+    pub legacy: bool,
+    pub version: Option<u32>,
+}
+
+impl Negotiation {
+    pub fn versions(&self) -> RangeInclusive<u32> {
+        match self.version {
+            Some(v) => v..=v,
+            None if self.legacy => MIN_WIRE_VERSION..=WIRE_VERSION,
+            None => WIRE_VERSION..=WIRE_VERSION,
+        }
+    }
+
+    pub fn pin(&mut self, version: u32) {
+        self.version.get_or_insert(version);
+    }
+
+    pub fn is_default(&self) -> bool {
+        *self == Negotiation::default()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sender {
+    Server,
+    User(usize),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub version: u32,
+    pub session_id: SessionId,
+    pub round: Option<usize>,
+    pub sender: Sender,
+}
+
+impl Header {
+    pub fn new(session_id: SessionId, round: Option<usize>, sender: Sender) -> Self {
+        Header { version: WIRE_VERSION, session_id, round, sender }
+    }
+
+    // Checks that the frame is meant for the session and round we are in,
+    // and comes from whom we expect.
+    pub fn check(&self, session_id: &SessionId, round: Option<usize>, sender: Sender) -> Result<(), AggregationError> {
+        if self.session_id != *session_id {
+            Err(ErrorKind::SessionMismatch.into())
+        } else if self.round != round {
+            Err(ErrorKind::UnexpectedRound.into())
+        } else if self.sender != sender {
+            Err(ErrorKind::UnexpectedSender.into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+pub fn encode<T: Serialize + Cbor>(header: &Header, payload: &T) -> Result<Vec<u8>, AggregationError> {
+    let round = match header.round {
+        Some(r) => u8::try_from(r).map_err(|_| ErrorKind::Serialization)?,
+        None => NO_ROUND,
+    };
+    let sender = match header.sender {
+        Sender::Server => SERVER_ID,
+        Sender::User(id) => id as u64,
+    };
+    let payload = match header.version {
+        1 => bincode::serialize(payload).map_err(|_| ErrorKind::Serialization)?,
+        _ => cbor::encode(payload)?,
+    };
+    let len = u32::try_from(payload.len()).map_err(|_| ErrorKind::Serialization)?;
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&MAGIC);
+    frame.extend_from_slice(&header.version.to_le_bytes());
+    frame.extend_from_slice(&header.session_id);
+    frame.push(round);
+    frame.extend_from_slice(&sender.to_le_bytes());
+    frame.extend_from_slice(&len.to_le_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+// Reads the header of a frame, and returns it along with the payload.
+pub fn decode_header(frame: &[u8]) -> Result<(Header, &[u8]), AggregationError> {
+    if frame.len() < MAGIC.len() || frame[..MAGIC.len()] != MAGIC {
+        return Err(ErrorKind::BadMagic.into())
+    }
+    if frame.len() < HEADER_LEN {
+        return Err(ErrorKind::LengthMismatch { expected: HEADER_LEN, received: frame.len() }.into())
+    }
+    let (header, payload) = frame.split_at(HEADER_LEN);
+    let version = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    if !(MIN_WIRE_VERSION..=WIRE_VERSION).contains(&version) {
+        return Err(ErrorKind::UnsupportedVersion { supported: WIRE_VERSION, received: version }.into())
+    }
+    let session_id = header[8..24].try_into().unwrap();
+    let round = match header[24] {
+        NO_ROUND => None,
+        r => Some(r as usize),
+    };
+    let sender = match u64::from_le_bytes(header[25..33].try_into().unwrap()) {
+        SERVER_ID => Sender::Server,
+        id => Sender::User(usize::try_from(id).map_err(|_| ErrorKind::MalformedMessage)?),
+    };
+    let len = u32::from_le_bytes(header[33..37].try_into().unwrap()) as usize;
+    if payload.len() != len {
+        return Err(ErrorKind::LengthMismatch { expected: HEADER_LEN + len, received: frame.len() }.into())
+    }
+    Ok((Header { version, session_id, round, sender }, payload))
+}
+
+// Reads the payload of a frame, once checked that it is meant for us, in a
+// version `negotiation` allows.
+pub fn decode<T: DeserializeOwned + Cbor>(
+    frame: &[u8],
+    session_id: &SessionId,
+    round: Option<usize>,
+    sender: Sender,
+    negotiation: &Negotiation,
+) -> Result<(Header, T), AggregationError> {
+    let (header, payload) = decode_header(frame)?;
+    let versions = negotiation.versions();
+    if !versions.contains(&header.version) {
+        return Err(ErrorKind::UnsupportedVersion { supported: *versions.end(), received: header.version }.into())
+    }
+    header.check(session_id, round, sender)?;
+    let payload = match header.version {
+        1 => bincode::deserialize(payload).map_err(|_| ErrorKind::MalformedMessage)?,
+        _ => cbor::decode(payload)?,
+    };
+    Ok((header, payload))
+}
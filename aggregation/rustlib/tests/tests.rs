@@ -1,21 +1,42 @@
 
 use std::sync::Arc;
-use std::sync::Once;
+use std::sync::{Mutex, Once};
+use std::time::{Duration, Instant};
 use std::num::Wrapping;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 
 use libsodium_sys::sodium_init;
 use rand::SeedableRng;
 use rand_chacha::ChaCha8Rng;
 use rand::seq::SliceRandom;
+use serde::{Serialize, Deserialize};
 
+use aggregation::error::*;
 use aggregation::sodium_bindings::*;
+use aggregation::secret::Secret;
+use aggregation::helpers::*;
+use aggregation::encoding::*;
+use aggregation::dp::*;
+use aggregation::ring::*;
+use aggregation::shamir;
+use aggregation::graph::*;
+use aggregation::cohort::*;
+use aggregation::snapshot::SealingKey;
+use aggregation::policy::*;
+use aggregation::format::FORMAT_VERSION;
+use aggregation::wire::{self, Header, Sender, HEADER_LEN, WIRE_VERSION};
+use aggregation::cbor;
 use aggregation::types::*;
 use aggregation::user::*;
 use aggregation::server::*;
 
 static INIT: Once = Once::new();
 
+// Integer inputs, which the tests use throughout.
+fn integers<R: Ring>() -> FixedPointEncoding<R> {
+    FixedPointEncoding::new(1., 1000.).unwrap()
+}
+
 fn setup() {
     INIT.call_once(|| {
         let ret = unsafe {
@@ -28,58 +49,62 @@ fn setup() {
     })
 }
 
-fn general_test(
+fn general_test<R: Ring>(
     participants: usize,
     active_per_round: [usize; 5],
     threshold: usize,
     vec_len: usize
-)
+) -> Result<Vec<R>, AggregationError>
 {
     let ids = (0..participants).map(|u| 2 * u + 25).collect::<Vec<usize>>();
 
     let sign_keys = ids.iter().map(|u| {
         (*u, gen_sign_keypair())
     }).collect::<BTreeMap<usize, (SignPublicKey, SignSecretKey)>>();
-    let sign_pks = Arc::new(sign_keys.iter().map(|(u, (pk, _))| (*u, pk.clone())).collect::<BTreeMap<usize, SignPublicKey>>());
+    let sign_pks = Arc::new(sign_keys.iter().map(|(u, (pk, _))| (*u, *pk)).collect::<BTreeMap<usize, SignPublicKey>>());
 
-    let mut users = sign_keys.into_iter().enumerate().map(|(i, (u, (sign_pk, sign_sk)))| {
+    let inputs = ids.iter().enumerate().map(|(i, u)| {
         let vec = (0..vec_len)
             .map(|j| if (j % participants) == i { j as i64 + 1 } else { 0 })
-            .map(Wrapping).collect();
+            .map(R::from_i64).collect::<Vec<_>>();
         println!("user {} : {:?}", u, vec);
-        User::new(u, threshold, sign_pk, sign_sk, vec, Arc::clone(&sign_pks))
-    }).collect::<Vec<User>>();
+        (*u, vec)
+    }).collect::<BTreeMap<usize, Vec<R>>>();
+
+    let mut users = sign_keys.into_iter().map(|(u, (sign_pk, sign_sk))| {
+        User::new(u, threshold, vec_len, sign_pk, sign_sk, inputs[&u].clone(), Arc::clone(&sign_pks), &integers()).unwrap()
+    }).collect::<Vec<User<R>>>();
     
-    let mut server = Server::new(threshold, vec_len);
+    let mut server = Server::<R>::new(threshold, vec_len, Arc::clone(&sign_pks), &integers()).unwrap();
 
     let mut msgs: BTreeMap<usize, UserInput> = users.iter().map(|u| (u.id(), UserInput::Round0())).collect();
 
     let masks = active_per_round.into_iter().map(|active| {
         let mut mask = (0..participants)
-            .map(|u| if u < active { true } else { false })
+            .map(|u| u < active)
             .collect::<Vec<bool>>();
         let mut rng = ChaCha8Rng::seed_from_u64(45);
         mask.shuffle(&mut rng);
-        Iterator::zip(ids.iter(), mask.into_iter()).map(|(u, b)| (*u, b)).collect::<BTreeMap<usize, bool>>()
+        Iterator::zip(ids.iter(), mask).map(|(u, b)| (*u, b)).collect::<BTreeMap<usize, bool>>()
     }).collect::<Vec<_>>();
 
     let mut round = 0;
     let vec = loop {
         println!("Round {}; dropped: {:?}", round, masks[round].iter().filter(|(_, b)| !**b).map(|(u, _)| u).collect::<Vec<_>>());
-        users.iter_mut().for_each(|u| {
+        for u in users.iter_mut() {
             if *masks[round].get(&u.id()).unwrap() {
                 let input = msgs.remove(&u.id()).unwrap();
-                let output = u.round(input).unwrap();
-                server.recv(u.id(), output).unwrap();
+                let output = u.round(input)?;
+                server.recv(u.id(), output)?;
             }
-        });
+        }
 
-        match server.round().unwrap() {
+        match server.round()? {
             ServerOutput::Messages(m) => {
                 msgs = m
             },
-            ServerOutput::Vector(vec) => {
-                break vec
+            ServerOutput::Aggregate(aggregate) => {
+                break aggregate.vec
             },
         }
 
@@ -88,6 +113,15 @@ fn general_test(
 
     println!("{:?}", vec);
 
+    // The masked inputs collected in Round 2 are the ones in the aggregate.
+    let expected = (0..vec_len).map(|j| {
+        inputs.iter()
+            .filter(|(u, _)| masks[2][u])
+            .fold(R::zero(), |acc, (_, v)| acc.add(v[j]))
+    }).collect::<Vec<_>>();
+    assert_eq!(vec, expected);
+
+    Ok(vec)
 }
 
 #[test]
@@ -98,7 +132,7 @@ fn simple_case() {
     let active_per_round = [9, 9, 9, 9, 9];
     let threshold = 5;
     let vec_len = 9;
-    general_test(participants, active_per_round, threshold, vec_len);
+    general_test::<Wrapping<i64>>(participants, active_per_round, threshold, vec_len).unwrap();
 }
 
 #[test]
@@ -109,11 +143,689 @@ fn with_dropping_users() {
     let active_per_round = [12, 11, 10, 9, 8];
     let threshold = 5;
     let vec_len = 15;
-    general_test(participants, active_per_round, threshold, vec_len);
+    general_test::<Wrapping<i64>>(participants, active_per_round, threshold, vec_len).unwrap();
+}
+
+#[test]
+fn with_dropping_users_in_small_rings() {
+    setup();
+
+    let participants = 13;
+    let active_per_round = [12, 11, 10, 9, 8];
+    let threshold = 5;
+    let vec_len = 15;
+    general_test::<Wrapping<u16>>(participants, active_per_round, threshold, vec_len).unwrap();
+    general_test::<Zq<1_000_003>>(participants, active_per_round, threshold, vec_len).unwrap();
+}
+
+#[test]
+fn masked_inputs_are_packed() {
+    let vec = (0..1000).map(|x| Zq::<1_000_003>::new(x * 997)).collect::<Vec<_>>();
+    let bytes = bincode::serialize(&UserOutput::Round2(vec.clone())).unwrap();
+    // 20 bits per element, plus the variant tag and two lengths.
+    assert_eq!(bytes.len(), 4 + 8 + 8 + 2500);
+
+    match bincode::deserialize::<UserOutput<Zq<1_000_003>>>(&bytes).unwrap() {
+        UserOutput::Round2(v) => assert_eq!(v, vec),
+        _ => panic!("expected a masked input"),
+    }
+}
+
+fn check_mask_kernels<R: Ring>() {
+    let seed = [7; 32];
+    let v = (0..1000).map(|x| R::from_i64(x * 31 - 500)).collect::<Vec<R>>();
+    let mask = vector_from_seed::<R>(seed, v.len());
+
+    let mut acc = v.clone();
+    add_mask(&mut acc, seed);
+    assert_eq!(acc, Iterator::zip(v.iter(), mask.iter()).map(|(a, b)| a.add(*b)).collect::<Vec<R>>());
+    sub_mask(&mut acc, seed);
+    assert_eq!(acc, v);
+
+    add_assign(&mut acc, &mask);
+    sub_assign(&mut acc, &v);
+    assert_eq!(acc, mask);
+
+    // With or without the `parallel` feature, the result is the same as
+    // applying the masks one after another.
+    let masks = (0..64u8)
+        .map(|i| (if i % 3 == 0 { Sign::Minus } else { Sign::Plus }, [i; 32]))
+        .collect::<Vec<_>>();
+    let mut expected = v.clone();
+    for (sign, seed) in masks.iter() {
+        match sign {
+            Sign::Plus => add_mask(&mut expected, *seed),
+            Sign::Minus => sub_mask(&mut expected, *seed),
+        }
+    }
+    let mut acc = v.clone();
+    apply_masks(&mut acc, &masks);
+    assert_eq!(acc, expected);
+}
+
+#[test]
+fn mask_kernels_match_expanded_masks() {
+    check_mask_kernels::<Wrapping<i64>>();
+    check_mask_kernels::<Wrapping<u16>>();
+    check_mask_kernels::<Zq<1_000_003>>();
+}
+
+#[test]
+fn weighted_aggregation() {
+    setup();
+
+    let participants = 7;
+    let threshold = 4;
+    let vec_len = 3;
+    let dropped = 5;
+
+    let encoding = integers().with_max_weight(participants as u64).unwrap();
+    let sign_keys = (0..participants).map(|u| (u, gen_sign_keypair())).collect::<BTreeMap<_, _>>();
+    let sign_pks = Arc::new(sign_keys.iter().map(|(u, (pk, _))| (*u, *pk)).collect::<BTreeMap<_, _>>());
+    let mut users = sign_keys.into_iter().map(|(u, (sign_pk, sign_sk))| {
+        let vec = vec![Wrapping(u as i64), Wrapping(1), Wrapping(-(u as i64))];
+        User::new_weighted(u, threshold, vec_len, sign_pk, sign_sk, vec, Wrapping(u as i64 + 1), Arc::clone(&sign_pks), &encoding).unwrap()
+    }).collect::<Vec<User>>();
+    let mut server = Server::new_weighted(threshold, vec_len, Arc::clone(&sign_pks), &encoding).unwrap();
+
+    let mut round = 0;
+    let mut msgs: BTreeMap<usize, UserInput> = users.iter().map(|u| (u.id(), UserInput::Round0())).collect();
+    let aggregate = loop {
+        for u in users.iter_mut() {
+            // One user drops before sending its masked input.
+            if round >= 2 && u.id() == dropped {
+                continue
+            }
+            if let Some(input) = msgs.remove(&u.id()) {
+                server.recv(u.id(), u.round(input).unwrap()).unwrap();
+            }
+        }
+
+        match server.round().unwrap() {
+            ServerOutput::Messages(m) => msgs = m,
+            ServerOutput::Aggregate(aggregate) => break aggregate,
+        }
+        round += 1;
+    };
+
+    let included = (0..participants).filter(|u| *u != dropped).collect::<BTreeSet<usize>>();
+    let weights = included.iter().map(|u| *u as i64 + 1);
+    let weighted = included.iter().map(|u| (*u as i64 + 1) * *u as i64).sum::<i64>();
+    assert_eq!(aggregate.users, included);
+    assert_eq!(aggregate.weight, Some(Wrapping(weights.clone().sum())));
+    assert_eq!(aggregate.vec, vec![Wrapping(weighted), Wrapping(weights.sum()), Wrapping(-weighted)]);
+}
+
+#[test]
+fn masked_input_resubmission() {
+    setup();
+
+    let participants = 5;
+    let threshold = 3;
+    let vec_len = 4;
+
+    let sign_keys = (0..participants).map(|u| (u, gen_sign_keypair())).collect::<BTreeMap<_, _>>();
+    let sign_pks = Arc::new(sign_keys.iter().map(|(u, (pk, _))| (*u, *pk)).collect::<BTreeMap<_, _>>());
+    let mut users = sign_keys.into_iter().map(|(u, (sign_pk, sign_sk))| {
+        User::new(u, threshold, vec_len, sign_pk, sign_sk, vec![Wrapping(u as i64); vec_len], Arc::clone(&sign_pks), &integers()).unwrap()
+    }).collect::<Vec<User>>();
+    let mut server = Server::new(threshold, vec_len, Arc::clone(&sign_pks), &integers()).unwrap();
+
+    let mut msgs: BTreeMap<usize, UserInput> = users.iter().map(|u| (u.id(), UserInput::Round0())).collect();
+    let aggregate = loop {
+        for u in users.iter_mut() {
+            if let Some(input) = msgs.remove(&u.id()) {
+                match u.round(input).unwrap() {
+                    UserOutput::Round2(masked) => {
+                        // A retransmission is ignored, a different input rejected.
+                        server.recv(u.id(), UserOutput::Round2(masked.clone())).unwrap();
+                        server.recv(u.id(), UserOutput::Round2(masked.clone())).unwrap();
+                        let mut other = masked;
+                        other[0] += Wrapping(1);
+                        let err = server.recv(u.id(), UserOutput::Round2(other)).unwrap_err();
+                        assert_eq!((err.round, err.user), (Some(2), Some(u.id())));
+                        assert_eq!(err.kind, ErrorKind::Equivocation);
+                        // Neither truncated nor extended inputs are summed.
+                        for len in [vec_len - 1, vec_len + 1] {
+                            let err = server.recv(u.id(), UserOutput::Round2(vec![Wrapping(1); len])).unwrap_err();
+                            assert_eq!((err.round, err.user), (Some(2), Some(u.id())));
+                            assert_eq!(err.kind, ErrorKind::LengthMismatch { expected: vec_len, received: len });
+                        }
+                    },
+                    output => server.recv(u.id(), output).unwrap(),
+                }
+            }
+        }
+
+        match server.round().unwrap() {
+            ServerOutput::Messages(m) => msgs = m,
+            ServerOutput::Aggregate(aggregate) => break aggregate,
+        }
+    };
+
+    // Conflicting masked inputs cannot be excluded, only reported.
+    assert_eq!(aggregate.vec, vec![Wrapping(10); vec_len]);
+    assert_eq!(aggregate.equivocators, aggregate.users);
+}
+
+#[test]
+fn conflicting_messages_are_excluded() {
+    setup();
+
+    let participants = 5;
+    let threshold = 3;
+    let vec_len = 4;
+
+    let sign_keys = (0..participants).map(|u| (u, gen_sign_keypair())).collect::<BTreeMap<_, _>>();
+    let sign_pks = Arc::new(sign_keys.iter().map(|(u, (pk, _))| (*u, *pk)).collect::<BTreeMap<_, _>>());
+    let mut users = sign_keys.into_iter().map(|(u, (sign_pk, sign_sk))| {
+        User::new(u, threshold, vec_len, sign_pk, sign_sk, vec![Wrapping(u as i64); vec_len], Arc::clone(&sign_pks), &integers()).unwrap()
+    }).collect::<Vec<User>>();
+    let mut server = Server::new(threshold, vec_len, Arc::clone(&sign_pks), &integers()).unwrap();
+
+    let mut msgs: BTreeMap<usize, UserInput> = users.iter().map(|u| (u.id(), UserInput::Round0())).collect();
+    let aggregate = loop {
+        for u in users.iter_mut() {
+            if let Some(input) = msgs.remove(&u.id()) {
+                match u.round(input).unwrap() {
+                    // User 0 advertises two different sets of keys.
+                    UserOutput::Round0(comm_pk, rand_pk) if u.id() == 0 => {
+                        server.recv(0, UserOutput::Round0(comm_pk.clone(), rand_pk)).unwrap();
+                        let err = server.recv(0, UserOutput::Round0(comm_pk.clone(), comm_pk)).unwrap_err();
+                        assert_eq!((err.round, err.user, err.kind), (Some(0), Some(0), ErrorKind::Equivocation));
+                    },
+                    // User 2 reveals two different sets of shares.
+                    UserOutput::Round4(shares) if u.id() == 2 => {
+                        let header = Header::new(SessionId::default(), Some(4), Sender::User(2));
+                        let bytes = wire::encode(&header, &UserOutput::<Wrapping<i64>>::Round4(shares)).unwrap();
+                        server.recv_serialized(2, &bytes).unwrap();
+                        server.recv_serialized(2, &bytes).unwrap();
+                        let payload = wire::decode_header(&bytes).unwrap().1;
+                        let Ok(UserOutput::<Wrapping<i64>>::Round4(mut other)) = cbor::decode(payload) else { unreachable!() };
+                        other.pop_first();
+                        let err = server.recv(2, UserOutput::Round4(other)).unwrap_err();
+                        assert_eq!((err.round, err.user, err.kind), (Some(4), Some(2), ErrorKind::Equivocation));
+                    },
+                    output => server.recv(u.id(), output).unwrap(),
+                }
+            }
+        }
+
+        match server.round().unwrap() {
+            ServerOutput::Messages(m) => {
+                msgs = m;
+                // The server restarts from its state, which tells who equivocated.
+                let state = server.serialize_state().unwrap();
+                server = Server::new(threshold, vec_len, Arc::clone(&sign_pks), &integers()).unwrap();
+                server.recover_state(&state).unwrap();
+            },
+            ServerOutput::Aggregate(aggregate) => break aggregate,
+        }
+    };
+
+    // User 0 never took part, user 2 did until its shares were discarded.
+    assert_eq!(aggregate.vec, vec![Wrapping(10); vec_len]);
+    assert_eq!(aggregate.users, BTreeSet::from([1, 2, 3, 4]));
+    assert_eq!(aggregate.equivocators, BTreeSet::from([0, 2]));
+    assert!(server.equivocators().is_empty());
+}
+
+#[test]
+fn sparse_graph_aggregation() {
+    setup();
+
+    let participants = 20;
+    let threshold = 10;
+    let graph = SparseGraph::new(4, 3).unwrap();
+    let vec_len = 4;
+    // One user drops before sending its masked input, one before unmasking.
+    let (dropped, late) = (3, 8);
+
+    let sign_keys = (0..participants).map(|u| (u, gen_sign_keypair())).collect::<BTreeMap<_, _>>();
+    let sign_pks = Arc::new(sign_keys.iter().map(|(u, (pk, _))| (*u, *pk)).collect::<BTreeMap<_, _>>());
+    let mut users = sign_keys.into_iter().map(|(u, (sign_pk, sign_sk))| {
+        User::new(u, graph.threshold, vec_len, sign_pk, sign_sk, vec![Wrapping(u as i64); vec_len], Arc::clone(&sign_pks), &integers()).unwrap()
+            .with_sparse_graph()
+    }).collect::<Vec<User>>();
+    let mut server = Server::new(threshold, vec_len, Arc::clone(&sign_pks), &integers()).unwrap().with_sparse_graph(graph);
+
+    let mut round = 0;
+    let mut msgs: BTreeMap<usize, UserInput> = users.iter().map(|u| (u.id(), UserInput::Round0())).collect();
+    let aggregate = loop {
+        for u in users.iter_mut() {
+            if (round >= 2 && u.id() == dropped) || (round >= 4 && u.id() == late) {
+                continue
+            }
+            if let Some(input) = msgs.remove(&u.id()) {
+                // Every user only gets the keys of its 4 neighbours and its own.
+                if let UserInput::Round1(keys) = &input {
+                    assert_eq!(keys.len(), 5);
+                    assert!(keys.contains_key(&u.id()));
+                }
+                server.recv(u.id(), u.round(input).unwrap()).unwrap();
+            }
+        }
+
+        match server.round().unwrap() {
+            ServerOutput::Messages(m) => msgs = m,
+            ServerOutput::Aggregate(aggregate) => break aggregate,
+        }
+        round += 1;
+    };
+
+    let expected = (0..participants).filter(|u| *u != dropped).map(|u| u as i64).sum::<i64>();
+    assert_eq!(aggregate.vec, vec![Wrapping(expected); vec_len]);
+    assert!(SparseGraph::new(3, 2).is_err());
+    assert!(SparseGraph::new(4, 6).is_err());
+}
+
+#[test]
+fn dense_graph_keeps_the_session_threshold() {
+    setup();
+
+    let participants = 5;
+    let threshold = 4;
+    // Every user would be a neighbour of all the others.
+    let graph = SparseGraph::new(4, 2).unwrap();
+    let vec_len = 2;
+
+    let sign_keys = (0..participants).map(|u| (u, gen_sign_keypair())).collect::<BTreeMap<_, _>>();
+    let sign_pks = Arc::new(sign_keys.iter().map(|(u, (pk, _))| (*u, *pk)).collect::<BTreeMap<_, _>>());
+    let mut users = sign_keys.into_iter().map(|(u, (sign_pk, sign_sk))| {
+        User::new(u, graph.threshold, vec_len, sign_pk, sign_sk, vec![Wrapping(u as i64); vec_len], Arc::clone(&sign_pks), &integers()).unwrap()
+            .with_sparse_graph()
+    }).collect::<Vec<User>>();
+    let mut server = Server::new(threshold, vec_len, Arc::clone(&sign_pks), &integers()).unwrap().with_sparse_graph(graph);
+
+    let mut msgs: BTreeMap<usize, UserInput> = users.iter().map(|u| (u.id(), UserInput::Round0())).collect();
+    let err = loop {
+        for u in users.iter_mut() {
+            if let Some(input) = msgs.remove(&u.id()) {
+                match u.round(input).unwrap() {
+                    // Only 2 users reveal their share of the seed of user 0.
+                    UserOutput::Round4(mut shares) if u.id() < 3 => {
+                        shares.remove(&0);
+                        server.recv(u.id(), UserOutput::Round4(shares)).unwrap();
+                    },
+                    output => server.recv(u.id(), output).unwrap(),
+                }
+            }
+        }
+
+        match server.round() {
+            Ok(ServerOutput::Messages(m)) => msgs = m,
+            Ok(ServerOutput::Aggregate(_)) => panic!("unmasked with too few shares"),
+            Err(e) => break e,
+        }
+    };
+
+    assert_eq!((err.user, err.kind), (Some(0), ErrorKind::BelowThreshold { threshold: 4, received: 2 }));
+}
+
+#[test]
+fn multi_iteration_session() {
+    setup();
+
+    let participants = 8;
+    let threshold = 4;
+    let vec_len = 3;
+    let iterations = 3;
+    // One user drops before sending its masked input in the first iteration,
+    // another one before unmasking in the second: neither comes back.
+    let (dropped, late) = (2, 5);
+
+    let sign_keys = (0..participants).map(|u| (u, gen_sign_keypair())).collect::<BTreeMap<_, _>>();
+    let sign_pks = Arc::new(sign_keys.iter().map(|(u, (pk, _))| (*u, *pk)).collect::<BTreeMap<_, _>>());
+    let mut users = sign_keys.into_iter().map(|(u, (sign_pk, sign_sk))| {
+        User::new(u, threshold, vec_len, sign_pk, sign_sk, vec![Wrapping(u as i64); vec_len], Arc::clone(&sign_pks), &integers()).unwrap()
+            .with_iterations(iterations)
+    }).collect::<Vec<User>>();
+    let mut server = Server::new(threshold, vec_len, Arc::clone(&sign_pks), &integers()).unwrap().with_iterations(iterations);
+
+    let mut msgs: BTreeMap<usize, UserInput> = users.iter().map(|u| (u.id(), UserInput::Round0())).collect();
+    let mut aggregates = vec![];
+    let mut round = 0;
+    while aggregates.len() < iterations {
+        let iteration = aggregates.len();
+        for u in users.iter_mut() {
+            if (u.id() == dropped && round >= 2) || (u.id() == late && iteration == 1 && round >= 4) {
+                continue
+            }
+            if let Some(input) = msgs.remove(&u.id()) {
+                server.recv(u.id(), u.round(input).unwrap()).unwrap();
+            }
+        }
+
+        match server.round().unwrap() {
+            ServerOutput::Messages(m) => {
+                msgs = m;
+                round += 1;
+            },
+            ServerOutput::Aggregate(aggregate) => {
+                aggregates.push(aggregate);
+                if aggregates.len() < iterations {
+                    // Only Rounds 2 to 4 are run again, with new inputs.
+                    for u in users.iter_mut() {
+                        let id = u.id() as i64;
+                        u.set_input(vec![Wrapping(10 * aggregates.len() as i64 + id); vec_len]).unwrap();
+                    }
+                    msgs = match server.round().unwrap() {
+                        ServerOutput::Messages(m) => m,
+                        _ => panic!("expected the next iteration to start"),
+                    };
+                    assert!(msgs.values().all(|m| matches!(m, UserInput::NextIteration(_))));
+                    round = 2;
+                }
+            },
+        }
+    }
+
+    let expected_users = [
+        (0..participants).filter(|u| *u != dropped).collect::<BTreeSet<usize>>(),
+        (0..participants).filter(|u| *u != dropped).collect(),
+        (0..participants).filter(|u| *u != dropped && *u != late).collect(),
+    ];
+    for (i, (aggregate, users)) in Iterator::zip(aggregates.iter(), expected_users.iter()).enumerate() {
+        let expected = users.iter().map(|u| 10 * i as i64 + *u as i64).sum::<i64>();
+        assert_eq!(&aggregate.users, users);
+        assert_eq!(aggregate.vec, vec![Wrapping(expected); vec_len]);
+    }
+    assert_eq!(server.round().err().unwrap().kind, ErrorKind::UnexpectedRound);
+}
+
+#[test]
+fn no_dropouts_after_the_first_iteration() {
+    setup();
+
+    let participants = 6;
+    let threshold = 3;
+    let vec_len = 2;
+    let iterations = 2;
+    // Alive in the first iteration, so that its seed is revealed, this user
+    // drops before sending its masked input in the second one.
+    let late = 4;
+
+    let sign_keys = (0..participants).map(|u| (u, gen_sign_keypair())).collect::<BTreeMap<_, _>>();
+    let sign_pks = Arc::new(sign_keys.iter().map(|(u, (pk, _))| (*u, *pk)).collect::<BTreeMap<_, _>>());
+    let mut users = sign_keys.into_iter().map(|(u, (sign_pk, sign_sk))| {
+        User::new(u, threshold, vec_len, sign_pk, sign_sk, vec![Wrapping(u as i64); vec_len], Arc::clone(&sign_pks), &integers()).unwrap()
+            .with_iterations(iterations)
+            .with_threat_model(ThreatModel::SemiHonest)
+    }).collect::<Vec<User>>();
+    let mut server = Server::new(threshold, vec_len, Arc::clone(&sign_pks), &integers()).unwrap()
+        .with_iterations(iterations)
+        .with_threat_model(ThreatModel::SemiHonest);
+
+    let mut msgs: BTreeMap<usize, UserInput> = users.iter().map(|u| (u.id(), UserInput::Round0())).collect();
+    let err = loop {
+        for u in users.iter_mut() {
+            if u.id() == late && matches!(msgs.get(&u.id()), Some(UserInput::NextIteration(_))) {
+                continue
+            }
+            if let Some(input) = msgs.remove(&u.id()) {
+                server.recv(u.id(), u.round(input).unwrap()).unwrap();
+            }
+        }
+
+        match server.round() {
+            Ok(ServerOutput::Messages(m)) => msgs = m,
+            // The next iteration starts right away.
+            Ok(ServerOutput::Aggregate(_)) => {},
+            Err(e) => break e,
+        }
+    };
+    assert_eq!(err.kind, ErrorKind::BelowThreshold { threshold: participants, received: participants - 1 });
+
+    // Nor does a user reveal the keys of the late one to a server which
+    // would carry on.
+    let alive = (0..participants).filter(|u| *u != late).collect::<Vec<usize>>();
+    let err = users[0].round(UserInput::Round3(alive)).err().unwrap();
+    assert_eq!(err.kind, ErrorKind::BelowThreshold { threshold: participants, received: participants - 1 });
+}
+
+#[test]
+fn semi_honest_skips_consistency_check() {
+    setup();
+
+    let participants = 7;
+    let threshold = 4;
+    let vec_len = 3;
+    let dropped = 1;
+
+    let sign_keys = (0..participants).map(|u| (u, gen_sign_keypair())).collect::<BTreeMap<_, _>>();
+    let sign_pks = Arc::new(sign_keys.iter().map(|(u, (pk, _))| (*u, *pk)).collect::<BTreeMap<_, _>>());
+    let mut users = sign_keys.into_iter().map(|(u, (sign_pk, sign_sk))| {
+        User::new(u, threshold, vec_len, sign_pk, sign_sk, vec![Wrapping(u as i64); vec_len], Arc::clone(&sign_pks), &integers()).unwrap()
+            .with_threat_model(ThreatModel::SemiHonest)
+    }).collect::<Vec<User>>();
+    let mut server = Server::new(threshold, vec_len, Arc::clone(&sign_pks), &integers()).unwrap()
+        .with_threat_model(ThreatModel::SemiHonest);
+
+    let mut round_trips = 0;
+    let mut msgs: BTreeMap<usize, UserInput> = users.iter().map(|u| (u.id(), UserInput::Round0())).collect();
+    let vec = loop {
+        round_trips += 1;
+        for u in users.iter_mut() {
+            if round_trips > 2 && u.id() == dropped {
+                continue
+            }
+            if let Some(input) = msgs.remove(&u.id()) {
+                let output = u.round(input).unwrap();
+                assert!(!matches!(output, UserOutput::Round3(_)));
+                server.recv(u.id(), output).unwrap();
+            }
+        }
+
+        match server.round().unwrap() {
+            ServerOutput::Messages(m) => msgs = m,
+            ServerOutput::Aggregate(aggregate) => break aggregate.vec,
+        }
+    };
+
+    let expected = (0..participants).filter(|u| *u != dropped).map(|u| u as i64).sum::<i64>();
+    assert_eq!(vec, vec![Wrapping(expected); vec_len]);
+    assert_eq!(round_trips, 4);
+}
+
+// A clock which only moves when told to.
+struct ManualClock(Mutex<Instant>);
+
+impl ManualClock {
+    fn advance(&self, d: Duration) {
+        *self.0.lock().unwrap() += d;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+#[test]
+fn round_policy() {
+    setup();
+
+    let deadline = Duration::from_secs(10);
+    assert_eq!(RoundPolicy::new(2, 0., deadline).unwrap_err().kind, ErrorKind::InvalidParameters);
+    let policy = RoundPolicy::new(4, 0.8, deadline).unwrap();
+    assert_eq!(policy.status(3, 10, 7, Duration::ZERO), RoundStatus::Wait);
+    assert_eq!(policy.status(3, 10, 8, Duration::ZERO), RoundStatus::Ready);
+    assert_eq!(policy.status(3, 10, 4, deadline), RoundStatus::Ready);
+    assert_eq!(policy.status(3, 10, 3, deadline),
+        RoundStatus::Failed(ErrorKind::BelowThreshold { threshold: 4, received: 3 }.into()));
+    assert_eq!(policy.status(3, 3, 0, Duration::ZERO),
+        RoundStatus::Failed(ErrorKind::BelowThreshold { threshold: 4, received: 0 }.into()));
+
+    // Policies are checked when deserialized too.
+    let json = serde_json::to_value(&policy).unwrap();
+    assert_eq!(serde_json::from_value::<RoundPolicy>(json.clone()).unwrap(), policy);
+    let mut invalid = json;
+    invalid["target_fraction"] = (1.5).into();
+    assert!(serde_json::from_value::<RoundPolicy>(invalid).is_err());
+
+    let participants = 5;
+    let threshold = 3;
+    let vec_len = 4;
+    let dropped = 4;
+
+    let sign_keys = (0..participants).map(|u| (u, gen_sign_keypair())).collect::<BTreeMap<_, _>>();
+    let sign_pks = Arc::new(sign_keys.iter().map(|(u, (pk, _))| (*u, *pk)).collect::<BTreeMap<_, _>>());
+    let mut users = sign_keys.into_iter().map(|(u, (sign_pk, sign_sk))| {
+        User::new(u, threshold, vec_len, sign_pk, sign_sk, vec![Wrapping(u as i64); vec_len], Arc::clone(&sign_pks), &integers()).unwrap()
+    }).collect::<Vec<User>>();
+    let clock = Arc::new(ManualClock(Mutex::new(Instant::now())));
+    let mut server = Server::new(threshold, vec_len, Arc::clone(&sign_pks), &integers()).unwrap()
+        .with_policy(RoundPolicy::new(threshold, 1., deadline).unwrap())
+        .with_clock(clock.clone());
+
+    // One user never sends its masked input: the server waits for it until
+    // the deadline, every other round closes as soon as all users answered.
+    let mut msgs: BTreeMap<usize, UserInput> = users.iter().map(|u| (u.id(), UserInput::Round0())).collect();
+    let vec = loop {
+        for u in users.iter_mut() {
+            if let Some(input) = msgs.remove(&u.id()) {
+                match u.round(input).unwrap() {
+                    UserOutput::Round2(_) if u.id() == dropped => (),
+                    output => server.recv(u.id(), output).unwrap(),
+                }
+            }
+        }
+
+        if server.status() == RoundStatus::Wait {
+            clock.advance(deadline / 2);
+            assert_eq!(server.status(), RoundStatus::Wait);
+            clock.advance(deadline / 2);
+        }
+        assert_eq!(server.status(), RoundStatus::Ready);
+
+        match server.round().unwrap() {
+            ServerOutput::Messages(m) => msgs = m,
+            ServerOutput::Aggregate(aggregate) => break aggregate.vec,
+        }
+    };
+
+    assert_eq!(vec, vec![Wrapping(6); vec_len]);
+    assert_eq!(server.status(), RoundStatus::Done);
+}
+
+#[test]
+fn stray_senders_are_rejected() {
+    setup();
+
+    let participants = 6;
+    let threshold = 3;
+    let vec_len = 4;
+    // User 5 never advertises its keys, and user 4 skips the consistency check.
+    let (absent, silent) = (5, 4);
+
+    let sign_keys = (0..participants).map(|u| (u, gen_sign_keypair())).collect::<BTreeMap<_, _>>();
+    let sign_pks = Arc::new(sign_keys.iter().map(|(u, (pk, _))| (*u, *pk)).collect::<BTreeMap<_, _>>());
+    let mut users = sign_keys.into_iter().map(|(u, (sign_pk, sign_sk))| {
+        User::new(u, threshold, vec_len, sign_pk, sign_sk, vec![Wrapping(u as i64); vec_len], Arc::clone(&sign_pks), &integers()).unwrap()
+    }).collect::<Vec<User>>();
+    let mut server = Server::new(threshold, vec_len, Arc::clone(&sign_pks), &integers()).unwrap();
+
+    let mut msgs: BTreeMap<usize, UserInput> = users.iter().map(|u| (u.id(), UserInput::Round0())).collect();
+    msgs.remove(&absent);
+    let vec = loop {
+        for u in users.iter_mut() {
+            if let Some(input) = msgs.remove(&u.id()) {
+                match u.round(input).unwrap() {
+                    UserOutput::Round2(masked) => {
+                        let err = server.recv(absent, UserOutput::Round2(vec![Wrapping(100); vec_len])).unwrap_err();
+                        assert_eq!((err.round, err.user, err.kind), (Some(2), Some(absent), ErrorKind::UnexpectedSender));
+                        server.recv(u.id(), UserOutput::Round2(masked)).unwrap();
+                    },
+                    UserOutput::Round3(_) if u.id() == silent => (),
+                    UserOutput::Round4(shares) => {
+                        let err = server.recv(silent, UserOutput::Round4(BTreeMap::new())).unwrap_err();
+                        assert_eq!((err.round, err.user, err.kind), (Some(4), Some(silent), ErrorKind::UnexpectedSender));
+                        server.recv(u.id(), UserOutput::Round4(shares)).unwrap();
+                    },
+                    output => server.recv(u.id(), output).unwrap(),
+                }
+            }
+        }
+
+        match server.round().unwrap() {
+            ServerOutput::Messages(m) => msgs = m,
+            ServerOutput::Aggregate(aggregate) => break aggregate.vec,
+        }
+    };
+
+    // The input of the silent user is in the sum, as it was sent in Round 2.
+    assert_eq!(vec, vec![Wrapping(10); vec_len]);
+}
+
+#[test]
+fn registration_and_manifest() {
+    setup();
+
+    let participants = 5;
+    let threshold = 3;
+    let vec_len = 4;
+    let session_id = [7; 16];
+    let (coordinator_pk, coordinator_sk) = gen_sign_keypair();
+
+    let sign_keys = (0..participants).map(|u| (u, gen_sign_keypair())).collect::<BTreeMap<_, _>>();
+    let mut registrar = Registrar::new(session_id, threshold, vec_len);
+    for (u, (sign_pk, sign_sk)) in sign_keys.iter() {
+        let registration = Registration { session_id, id: *u, sign_pk: *sign_pk };
+        registrar.recv(Signed::wrap(registration.clone(), sign_sk)).unwrap();
+        // Registering twice is harmless, but not under another key.
+        registrar.recv(Signed::wrap(registration, sign_sk)).unwrap();
+        let (other_pk, other_sk) = gen_sign_keypair();
+        let other = Registration { session_id, id: *u, sign_pk: other_pk };
+        let err = registrar.recv(Signed::wrap(other, &other_sk)).unwrap_err();
+        assert_eq!((err.user, err.kind), (Some(*u), ErrorKind::Equivocation));
+        // Nobody can register a key it does not own.
+        let err = registrar.recv(Signed::wrap(Registration { session_id, id: 99, sign_pk: *sign_pk }, &other_sk)).unwrap_err();
+        assert_eq!((err.user, err.kind), (Some(99), ErrorKind::BadSignature));
+        let err = registrar.recv(Signed::wrap(Registration { session_id: [8; 16], id: *u, sign_pk: *sign_pk }, sign_sk)).unwrap_err();
+        assert_eq!((err.user, err.kind), (Some(*u), ErrorKind::SessionMismatch));
+    }
+    let manifest = registrar.publish(&coordinator_sk).unwrap();
+    assert_eq!(manifest.msg().members.len(), participants);
+
+    let sign_pk = sign_keys[&0].0;
+    let sign_sk = || SignSecretKey::from_slice(sign_keys[&0].1.expose()).unwrap();
+    let (_, forger_sk) = gen_sign_keypair();
+    let forged = Signed::wrap(manifest.msg().clone(), &forger_sk);
+    let err = User::<Wrapping<i64>>::from_manifest(0, sign_pk, sign_sk(), vec![Wrapping(0); vec_len], forged, &coordinator_pk, &session_id, &integers()).err().unwrap();
+    assert_eq!(err.kind, ErrorKind::BadSignature);
+    let err = User::<Wrapping<i64>>::from_manifest(0, sign_pk, sign_sk(), vec![Wrapping(0); vec_len], manifest.clone(), &coordinator_pk, &[8; 16], &integers()).err().unwrap();
+    assert_eq!(err.kind, ErrorKind::SessionMismatch);
+    let (other_pk, other_sk) = gen_sign_keypair();
+    let err = User::<Wrapping<i64>>::from_manifest(0, other_pk, other_sk, vec![Wrapping(0); vec_len], manifest.clone(), &coordinator_pk, &session_id, &integers()).err().unwrap();
+    assert_eq!((err.user, err.kind), (Some(0), ErrorKind::UnknownUser));
+
+    let mut users = sign_keys.into_iter().map(|(u, (sign_pk, sign_sk))| {
+        User::from_manifest(u, sign_pk, sign_sk, vec![Wrapping(u as i64); vec_len], manifest.clone(), &coordinator_pk, &session_id, &integers()).unwrap()
+    }).collect::<Vec<User>>();
+    let mut server = Server::from_manifest(manifest.msg(), &integers()).unwrap();
+
+    let mut msgs: BTreeMap<usize, UserInput> = users.iter().map(|u| (u.id(), UserInput::Round0())).collect();
+    let vec = loop {
+        for u in users.iter_mut() {
+            if let Some(input) = msgs.remove(&u.id()) {
+                server.recv(u.id(), u.round(input).unwrap()).unwrap();
+            }
+        }
+
+        match server.round().unwrap() {
+            ServerOutput::Messages(m) => msgs = m,
+            ServerOutput::Aggregate(aggregate) => break aggregate.vec,
+        }
+    };
+
+    assert_eq!(vec, vec![Wrapping(10); vec_len]);
+
+    let registrar = Registrar::new(session_id, threshold, vec_len);
+    assert_eq!(registrar.publish(&coordinator_sk).err().unwrap().kind, ErrorKind::BelowThreshold { threshold, received: 0 });
 }
 
 #[test]
-#[should_panic]
 fn below_threshold() {
     setup();
 
@@ -121,6 +833,797 @@ fn below_threshold() {
     let active_per_round = [9, 9, 9, 9, 4];
     let threshold = 7;
     let vec_len = 9;
-    general_test(participants, active_per_round, threshold, vec_len);
+    let err = general_test::<Wrapping<i64>>(participants, active_per_round, threshold, vec_len).unwrap_err();
+    assert_eq!(err.round, Some(4));
+    assert_eq!(err.kind, ErrorKind::BelowThreshold { threshold: 7, received: 4 });
+}
+
+
+#[test]
+fn input_length_is_checked() {
+    setup();
+
+    let (sign_pk, sign_sk) = gen_sign_keypair();
+    let sign_pks = Arc::new(BTreeMap::from([(1, sign_pk)]));
+    let err = User::new(1, 1, 4, sign_pk, sign_sk, vec![Wrapping(0i64); 3], Arc::clone(&sign_pks), &integers()).err().unwrap();
+    let (sign_pk, sign_sk) = gen_sign_keypair();
+    assert_eq!(err.user, Some(1));
+    assert_eq!(err.kind, ErrorKind::LengthMismatch { expected: 4, received: 3 });
+
+    let mut user = User::new(1, 1, 3, sign_pk, sign_sk, vec![Wrapping(0i64); 3], sign_pks, &integers()).unwrap();
+    let err = user.set_input(vec![Wrapping(0); 5]).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::LengthMismatch { expected: 3, received: 5 });
+}
+
+#[test]
+fn secrets_are_not_printed() {
+    setup();
+
+    let (_, sign_sk) = gen_sign_keypair();
+    assert_eq!(format!("{:?}", sign_sk), "Secret<64>(..)");
+
+    // Secrets still make it into the serialized state of a user.
+    let bytes = bincode::serialize(&sign_sk).unwrap();
+    let other: SignSecretKey = bincode::deserialize(&bytes).unwrap();
+    assert_eq!(other.expose(), sign_sk.expose());
+    assert!(bincode::deserialize::<Secret<32>>(&bytes).is_err());
+}
+
+#[test]
+fn sealed_state_snapshots() {
+    setup();
+
+    let participants = 5;
+    let threshold = 3;
+    let vec_len = 4;
+    let key = gen_aead_key();
+
+    let sign_keys = (0..participants).map(|u| (u, gen_sign_keypair())).collect::<BTreeMap<_, _>>();
+    let sign_pks = Arc::new(sign_keys.iter().map(|(u, (pk, _))| (*u, *pk)).collect::<BTreeMap<_, _>>());
+    let mut users = sign_keys.into_iter().map(|(u, (sign_pk, sign_sk))| {
+        User::new(u, threshold, vec_len, sign_pk, sign_sk, vec![Wrapping(u as i64); vec_len], Arc::clone(&sign_pks), &integers()).unwrap()
+    }).collect::<Vec<User>>();
+    let mut server = Server::new(threshold, vec_len, Arc::clone(&sign_pks), &integers()).unwrap();
+
+    // Every user goes through a snapshot between two rounds.
+    let mut blobs = vec![];
+    let mut msgs: BTreeMap<usize, UserInput> = users.iter().map(|u| (u.id(), UserInput::Round0())).collect();
+    let vec = loop {
+        for u in users.iter_mut() {
+            if let Some(input) = msgs.remove(&u.id()) {
+                server.recv(u.id(), u.round(input).unwrap()).unwrap();
+                let blob = u.seal_state(SealingKey::Key(&key)).unwrap();
+                u.open_state(&blob, SealingKey::Key(&key)).unwrap();
+                if u.id() == 0 {
+                    blobs.push(blob);
+                }
+            }
+        }
+
+        match server.round().unwrap() {
+            ServerOutput::Messages(m) => msgs = m,
+            ServerOutput::Aggregate(aggregate) => break aggregate.vec,
+        }
+    };
+    assert_eq!(vec, vec![Wrapping(10); vec_len]);
+
+    let user = &mut users[0];
+    assert_eq!(user.snapshot_counter(), blobs.len() as u64);
+    let mut tampered = blobs.last().unwrap().clone();
+    *tampered.last_mut().unwrap() ^= 1;
+    assert_eq!(user.open_state(&tampered, SealingKey::Key(&key)).unwrap_err().kind, ErrorKind::DecryptionFailure);
+    assert_eq!(user.open_state(blobs.last().unwrap(), SealingKey::Key(&gen_aead_key())).unwrap_err().kind, ErrorKind::DecryptionFailure);
+    assert_eq!(user.open_state(&blobs[0], SealingKey::Key(&key)).unwrap_err().kind,
+        ErrorKind::StaleSnapshot { latest: blobs.len() as u64, received: 1 });
+    // The latest snapshot can be restored again, e.g. after a crash.
+    user.open_state(blobs.last().unwrap(), SealingKey::Key(&key)).unwrap();
+
+    let blob = user.seal_state(SealingKey::Passphrase(b"correct horse")).unwrap();
+    assert_eq!(user.open_state(&blob, SealingKey::Passphrase(b"battery staple")).unwrap_err().kind, ErrorKind::DecryptionFailure);
+    assert_eq!(user.open_state(&blob, SealingKey::Key(&key)).unwrap_err().kind, ErrorKind::DecryptionFailure);
+    user.open_state(&blob, SealingKey::Passphrase(b"correct horse")).unwrap();
+
+    // A snapshot only restores the user and the session it was sealed for.
+    let blob = users[0].seal_state(SealingKey::Key(&key)).unwrap();
+    assert_eq!(users[1].open_state(&blob, SealingKey::Key(&key)).unwrap_err().kind, ErrorKind::DecryptionFailure);
+    let mut user = users.remove(0).with_session_id([1; 16]);
+    assert_eq!(user.open_state(&blob, SealingKey::Key(&key)).unwrap_err().kind, ErrorKind::DecryptionFailure);
+}
+
+#[test]
+fn resume_from_snapshots() {
+    setup();
+
+    let participants = 5;
+    let threshold = 3;
+    let vec_len = 4;
+    let key = gen_aead_key();
+
+    let sign_keys = (0..participants).map(|u| (u, gen_sign_keypair())).collect::<BTreeMap<_, _>>();
+    let sign_pks = Arc::new(sign_keys.iter().map(|(u, (pk, _))| (*u, *pk)).collect::<BTreeMap<_, _>>());
+    let mut users = sign_keys.into_iter().map(|(u, (sign_pk, sign_sk))| {
+        User::new(u, threshold, vec_len, sign_pk, sign_sk, vec![Wrapping(u as i64); vec_len], Arc::clone(&sign_pks), &integers()).unwrap()
+    }).collect::<Vec<User>>();
+    let mut server = Server::new(threshold, vec_len, Arc::clone(&sign_pks), &integers()).unwrap();
+
+    // Every process crashes and resumes from its snapshot between two rounds.
+    let mut blobs = vec![];
+    let mut msgs: BTreeMap<usize, UserInput> = users.iter().map(|u| (u.id(), UserInput::Round0())).collect();
+    let vec = loop {
+        for u in users.iter_mut() {
+            if let Some(input) = msgs.remove(&u.id()) {
+                server.recv(u.id(), u.round(input).unwrap()).unwrap();
+                let blob = u.seal_snapshot(SealingKey::Key(&key)).unwrap();
+                let (session_id, id, counter) = (*u.session_id(), u.id(), u.snapshot_counter());
+                *u = User::open_snapshot(&blob, &session_id, id, counter, SealingKey::Key(&key)).unwrap();
+                if id == 0 {
+                    blobs.push(blob);
+                }
+            }
+        }
+        server = Server::from_snapshot(&server.snapshot().unwrap()).unwrap();
+
+        let snapshot = server.snapshot().unwrap();
+        match server.round().unwrap() {
+            ServerOutput::Messages(m) => msgs = m,
+            ServerOutput::Aggregate(aggregate) => {
+                // Snapshots of a newer version or with another input length are refused.
+                let snapshot: serde_json::Value = serde_json::from_slice(&snapshot).unwrap();
+                let mut other = snapshot.clone();
+                other["version"] = (FORMAT_VERSION + 1).into();
+                assert_eq!(Server::<Wrapping<i64>>::from_snapshot(other.to_string().as_bytes()).err().unwrap().kind,
+                    ErrorKind::UnsupportedVersion { supported: FORMAT_VERSION, received: FORMAT_VERSION + 1 });
+                let mut other = snapshot;
+                other["body"]["vec_len"] = (vec_len + 1).into();
+                assert_eq!(Server::<Wrapping<i64>>::from_snapshot(other.to_string().as_bytes()).err().unwrap().kind,
+                    ErrorKind::LengthMismatch { expected: vec_len + 1, received: vec_len });
+                break aggregate.vec
+            },
+        }
+    };
+    assert_eq!(vec, vec![Wrapping(10); vec_len]);
+
+    // Sealed snapshots are refused for another user, or when older than the last one.
+    let (session_id, counter) = (*users[0].session_id(), users[0].snapshot_counter());
+    assert_eq!(User::<Wrapping<i64>>::open_snapshot(blobs.last().unwrap(), &session_id, 1, 0, SealingKey::Key(&key)).err().unwrap().kind,
+        ErrorKind::DecryptionFailure);
+    assert_eq!(User::<Wrapping<i64>>::open_snapshot(&blobs[0], &session_id, 0, counter, SealingKey::Key(&key)).err().unwrap().kind,
+        ErrorKind::StaleSnapshot { latest: counter, received: 1 });
+
+    let snapshot: serde_json::Value = serde_json::from_slice(&users[0].snapshot().unwrap()).unwrap();
+    let mut other = snapshot.clone();
+    other["body"]["data"]["vec_len"] = (vec_len + 1).into();
+    assert_eq!(User::<Wrapping<i64>>::from_snapshot(other.to_string().as_bytes()).err().unwrap().kind,
+        ErrorKind::LengthMismatch { expected: vec_len + 1, received: vec_len });
+    let mut other = snapshot;
+    other["body"]["data"]["sign_pk"] = serde_json::to_value(gen_sign_keypair().0).unwrap();
+    assert_eq!(User::<Wrapping<i64>>::from_snapshot(other.to_string().as_bytes()).err().unwrap().kind,
+        ErrorKind::InvalidParameters);
+}
+
+const GOLDEN: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden/");
+
+fn golden(name: &str) -> Vec<u8> {
+    std::fs::read(format!("{}{}", GOLDEN, name)).unwrap()
+}
+
+// The states of version 0, one per line, as written by the code of that
+// version in each round of a session and once failed, and the result of
+// their migration, as JSON.
+fn migrate_v0_states(name: &str, mut recover: impl FnMut(&str) -> Result<String, AggregationError>) -> String {
+    String::from_utf8(golden(&format!("{}-v0.jsonl", name))).unwrap().lines().map(|line| {
+        let migrated = recover(line).map(|s| serde_json::from_str::<serde_json::Value>(&s).unwrap());
+        serde_json::to_string(&migrated).unwrap() + "\n"
+    }).collect()
+}
+
+// Writes the golden files of the current version from the latest ones, to
+// be run once, with UPDATE_GOLDEN set, when the format changes: files of the
+// older versions are kept as they are.
+fn write_golden_files() {
+    let latest = |name: &str| (1..=FORMAT_VERSION).rev()
+        .find_map(|v| std::fs::read(format!("{}{}-v{}.json", GOLDEN, name, v)).ok())
+        .unwrap();
+    let write = |name: &str, bytes: &[u8]| {
+        std::fs::write(format!("{}{}-v{}.json", GOLDEN, name, FORMAT_VERSION), bytes).unwrap()
+    };
+    let mut user = User::<Wrapping<i64>>::from_snapshot(&latest("user-snapshot")).unwrap();
+    write("user-snapshot", &user.snapshot().unwrap());
+    write("user-state", user.serialize_state().unwrap().as_bytes());
+    let mut server = Server::<Wrapping<i64>>::from_snapshot(&latest("server-snapshot")).unwrap();
+    write("server-snapshot", &server.snapshot().unwrap());
+    write("server-state", server.serialize_state().unwrap().as_bytes());
+
+    let user_states = migrate_v0_states("user-states", |s| { user.recover_state(s)?; user.serialize_state() });
+    write("user-states-v0-migrated", user_states.as_bytes());
+    let server_states = migrate_v0_states("server-states", |s| { server.recover_state(s)?; server.serialize_state() });
+    write("server-states-v0-migrated", server_states.as_bytes());
+}
+
+#[test]
+fn golden_files() {
+    setup();
+
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        write_golden_files();
+    }
+    let current = |name: &str| golden(&format!("{}-v{}.json", name, FORMAT_VERSION));
+
+    // Snapshots of every version are restored, and written in the current one.
+    for version in 1..=FORMAT_VERSION {
+        let user = User::<Wrapping<i64>>::from_snapshot(&golden(&format!("user-snapshot-v{}.json", version))).unwrap();
+        assert_eq!(user.snapshot().unwrap(), current("user-snapshot"));
+        let server = Server::<Wrapping<i64>>::from_snapshot(&golden(&format!("server-snapshot-v{}.json", version))).unwrap();
+        assert_eq!(server.snapshot().unwrap(), current("server-snapshot"));
+    }
+
+    // And so are states.
+    let mut user = User::<Wrapping<i64>>::from_snapshot(&current("user-snapshot")).unwrap();
+    let mut server = Server::<Wrapping<i64>>::from_snapshot(&current("server-snapshot")).unwrap();
+    for version in 1..=FORMAT_VERSION {
+        user.recover_state(std::str::from_utf8(&golden(&format!("user-state-v{}.json", version))).unwrap()).unwrap();
+        assert_eq!(user.serialize_state().unwrap().into_bytes(), current("user-state"));
+        server.recover_state(std::str::from_utf8(&golden(&format!("server-state-v{}.json", version))).unwrap()).unwrap();
+        assert_eq!(server.serialize_state().unwrap().into_bytes(), current("server-state"));
+    }
+
+    // The bare states of version 0 are migrated, but for the server in
+    // Round 4, which holds shares we cannot read anymore.
+    let user_states = migrate_v0_states("user-states", |s| { user.recover_state(s)?; user.serialize_state() });
+    assert_eq!(user_states.into_bytes(), current("user-states-v0-migrated"));
+    let server_states = migrate_v0_states("server-states", |s| { server.recover_state(s)?; server.serialize_state() });
+    assert_eq!(server_states.into_bytes(), current("server-states-v0-migrated"));
+
+    let v0 = |name: &str, round: usize| {
+        String::from_utf8(golden(&format!("{}-v0.jsonl", name))).unwrap().lines().nth(round).unwrap().to_string()
+    };
+    assert_eq!(server.recover_state(&v0("server-states", 4)).err().unwrap().kind,
+        ErrorKind::UnsupportedVersion { supported: FORMAT_VERSION, received: 0 });
+    // A failure of version 0 did not record its cause.
+    user.recover_state(&v0("user-states", 6)).unwrap();
+    assert_eq!(user.round(UserInput::Round0()).err().unwrap().kind, ErrorKind::Unknown);
+    // The server goes on from the masked inputs it received, now summed.
+    server.recover_state(&v0("server-states", 2)).unwrap();
+    match server.round().unwrap() {
+        ServerOutput::Messages(m) => assert_eq!(m.keys().cloned().collect::<Vec<_>>(), vec![0, 1, 2, 3]),
+        ServerOutput::Aggregate(_) => panic!("no aggregate before Round 4"),
+    }
+
+    // A state is not taken for one of another kind.
+    let server_state = String::from_utf8(current("server-state")).unwrap();
+    assert_eq!(user.recover_state(&server_state).err().unwrap().kind, ErrorKind::MalformedMessage);
+}
+
+#[test]
+fn framed_messages() {
+    setup();
+
+    let participants = 4;
+    let threshold = 3;
+    let vec_len = 2;
+    let session_id = [7; 16];
+    // Frames of version 1 are only read when allowed, and users answer them
+    // in kind: this user talks to the server as if it only wrote version 1.
+    let legacy = 3;
+
+    let sign_keys = (0..participants).map(|u| (u, gen_sign_keypair())).collect::<BTreeMap<_, _>>();
+    let sign_pks = Arc::new(sign_keys.iter().map(|(u, (pk, _))| (*u, *pk)).collect::<BTreeMap<_, _>>());
+    let mut users = sign_keys.into_iter().map(|(u, (sign_pk, sign_sk))| {
+        let user = User::new(u, threshold, vec_len, sign_pk, sign_sk, vec![Wrapping(u as i64); vec_len], Arc::clone(&sign_pks), &integers())
+            .unwrap()
+            .with_session_id(session_id);
+        if u == legacy { user.with_legacy_wire() } else { user }
+    }).collect::<Vec<User>>();
+    let mut server = Server::<Wrapping<i64>>::new(threshold, vec_len, Arc::clone(&sign_pks), &integers()).unwrap()
+        .with_session_id(session_id)
+        .with_legacy_wire();
+
+    let round0 = wire::encode(&Header::new(session_id, Some(0), Sender::Server), &UserInput::Round0()).unwrap();
+    let mut msgs: BTreeMap<usize, Vec<u8>> = users.iter().map(|u| (u.id(), round0.clone())).collect();
+    let to_v1 = |frame: &[u8]| {
+        let (header, payload) = wire::decode_header(frame).unwrap();
+        let input: UserInput = cbor::decode(payload).unwrap();
+        wire::encode(&Header { version: 1, ..header }, &input).unwrap()
+    };
+    let v1_round0 = to_v1(&round0);
+    assert_eq!(users[0].round_serialized(&v1_round0).unwrap_err().kind,
+        ErrorKind::UnsupportedVersion { supported: WIRE_VERSION, received: 1 });
+    let v1_output = users[legacy].round_serialized(&v1_round0).unwrap();
+    let mut strict = Server::<Wrapping<i64>>::new(threshold, vec_len, Arc::clone(&sign_pks), &integers()).unwrap()
+        .with_session_id(session_id);
+    assert_eq!(strict.recv_serialized(legacy, &v1_output).unwrap_err().kind,
+        ErrorKind::UnsupportedVersion { supported: WIRE_VERSION, received: 1 });
+    server.recv_serialized(legacy, &v1_output).unwrap();
+    msgs.remove(&legacy);
+    let mut replayed = None;
+    let vec = loop {
+        for u in users.iter_mut() {
+            if let Some(input) = msgs.remove(&u.id()) {
+                let input = if u.id() == legacy {
+                    // Once on version 1, the user reads no other.
+                    assert_eq!(u.round_serialized(&input).unwrap_err().kind,
+                        ErrorKind::UnsupportedVersion { supported: 1, received: WIRE_VERSION });
+                    to_v1(&input)
+                } else {
+                    input
+                };
+                let output = u.round_serialized(&input).unwrap();
+                let version = wire::decode_header(&output).unwrap().0.version;
+                assert_eq!(version, wire::decode_header(&input).unwrap().0.version);
+                server.recv_serialized(u.id(), &output).unwrap();
+                replayed.get_or_insert(output);
+            }
+        }
+        match server.round_serialized().unwrap() {
+            ServerOutputSerialized::Messages(m) => msgs = m,
+            ServerOutputSerialized::Aggregate(aggregate) => break aggregate.vec,
+        }
+
+        // Frames which are not meant for this session, round or sender are
+        // refused, with the reason why.
+        let frame = replayed.take().unwrap();
+        let err = server.recv_serialized(0, &frame).unwrap_err();
+        assert_eq!((err.user, err.kind), (Some(0), ErrorKind::UnexpectedRound));
+        let frame = users[1].round_serialized(&msgs[&1]).unwrap();
+        let err = server.recv_serialized(2, &frame).unwrap_err();
+        assert_eq!((err.user, err.kind), (Some(2), ErrorKind::UnexpectedSender));
+        let mut other = frame.clone();
+        other[8..24].copy_from_slice(&[8; 16]);
+        assert_eq!(server.recv_serialized(1, &other).unwrap_err().kind, ErrorKind::SessionMismatch);
+        other = frame.clone();
+        other[4..8].copy_from_slice(&(WIRE_VERSION + 1).to_le_bytes());
+        assert_eq!(server.recv_serialized(1, &other).unwrap_err().kind,
+            ErrorKind::UnsupportedVersion { supported: WIRE_VERSION, received: WIRE_VERSION + 1 });
+        // Nor does the server read from a user another version than its first.
+        let (header, payload) = wire::decode_header(&frame).unwrap();
+        let output: UserOutput<Wrapping<i64>> = cbor::decode(payload).unwrap();
+        other = wire::encode(&Header { version: 1, ..header }, &output).unwrap();
+        assert_eq!(server.recv_serialized(1, &other).unwrap_err().kind,
+            ErrorKind::UnsupportedVersion { supported: WIRE_VERSION, received: 1 });
+        assert_eq!(server.recv_serialized(1, &frame[1..]).unwrap_err().kind, ErrorKind::BadMagic);
+        assert_eq!(server.recv_serialized(1, &frame[..20]).unwrap_err().kind,
+            ErrorKind::LengthMismatch { expected: HEADER_LEN, received: 20 });
+        assert_eq!(server.recv_serialized(1, &frame[..frame.len() - 1]).unwrap_err().kind,
+            ErrorKind::LengthMismatch { expected: frame.len(), received: frame.len() - 1 });
+        // A user does not take a frame from another user for one of the server.
+        assert_eq!(users[2].round_serialized(&frame).unwrap_err().kind, ErrorKind::UnexpectedSender);
+        server.recv_serialized(1, &frame).unwrap();
+        msgs.remove(&1);
+    };
+    assert_eq!(vec, vec![Wrapping(6); vec_len]);
+}
+
+#[derive(Serialize, Deserialize)]
+struct Vector {
+    name: String,
+    #[serde(rename = "type")]
+    kind: String,
+    ring_bits: Option<u32>,
+    cbor: String,
+    diagnostic: String,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Vec<u8> {
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+}
+
+// CBOR diagnostic notation (RFC 8949, section 8), for the types the schema uses.
+fn diagnostic(value: &ciborium::value::Value) -> String {
+    use ciborium::value::Value;
+
+    let join = |values: Vec<String>| values.join(", ");
+    match value {
+        Value::Integer(x) => i128::from(*x).to_string(),
+        Value::Bytes(bytes) => format!("h'{}'", to_hex(bytes)),
+        Value::Array(values) => format!("[{}]", join(values.iter().map(diagnostic).collect())),
+        Value::Map(entries) =>
+            format!("{{{}}}", join(entries.iter().map(|(k, v)| format!("{}: {}", diagnostic(k), diagnostic(v))).collect())),
+        _ => unreachable!(),
+    }
 }
 
+// One message of every kind, made of recognizable bytes rather than actual
+// keys and ciphertexts.
+fn test_vectors() -> Vec<(&'static str, &'static str, Option<u32>, Vec<u8>)> {
+    let signed = |i: u8| Signed::from_parts([i; 32], [i + 1; 64]);
+    let crypto_msg = |i: u8| CryptoMsg { nonce: [i; 24], c: vec![i + 1; 40] };
+    let bundled = |i: u8| BundledSignature::new([i; 64]);
+    let share = |point: u64| shamir::Share { point, values: vec![point + 1, (1 << 61) - 2] };
+    let input = |m: UserInput| cbor::encode(&m).unwrap();
+    let output = |m: UserOutput| cbor::encode(&m).unwrap();
+    vec![
+        ("round0-input", "user-input", None, input(UserInput::Round0())),
+        ("round1-input", "user-input", None,
+            input(UserInput::Round1([(1, (signed(1), signed(3))), (2, (signed(5), signed(7)))].into()))),
+        ("round2-input", "user-input", None, input(UserInput::Round2([(1, crypto_msg(1)), (3, crypto_msg(3))].into()))),
+        ("round3-input", "user-input", None, input(UserInput::Round3(vec![1, 2, 3]))),
+        ("round4-input", "user-input", None, input(UserInput::Round4([(1, bundled(1)), (2, bundled(2))].into()))),
+        ("next-iteration", "user-input", None, input(UserInput::NextIteration(vec![1, 3]))),
+        ("round0-output", "user-output", Some(64), output(UserOutput::Round0(signed(1), signed(3)))),
+        ("round1-output", "user-output", Some(64), output(UserOutput::Round1([(2, crypto_msg(2)), (3, crypto_msg(3))].into()))),
+        ("round2-output", "user-output", Some(64), output(UserOutput::Round2(vec![Wrapping(1), Wrapping(-1), Wrapping(1 << 40)]))),
+        ("round2-output-u16", "user-output", Some(16),
+            cbor::encode(&UserOutput::Round2(vec![Wrapping(1u16), Wrapping(2), Wrapping(u16::MAX)])).unwrap()),
+        ("round3-output", "user-output", Some(64), output(UserOutput::Round3(bundled(1)))),
+        ("round4-output", "user-output", Some(64),
+            output(UserOutput::Round4([(1, RevealedShare::RandSk(share(3))), (2, RevealedShare::Seed(share(3)))].into()))),
+    ]
+}
+
+#[test]
+fn cbor_test_vectors() {
+    const VECTORS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/vectors/messages.json");
+
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        let vectors = test_vectors().into_iter().map(|(name, kind, ring_bits, bytes)| {
+            let value: ciborium::value::Value = ciborium::de::from_reader(&bytes[..]).unwrap();
+            Vector { name: name.into(), kind: kind.into(), ring_bits, cbor: to_hex(&bytes), diagnostic: diagnostic(&value) }
+        }).collect::<Vec<_>>();
+        std::fs::write(VECTORS, serde_json::to_string_pretty(&vectors).unwrap() + "\n").unwrap();
+    }
+    let vectors: Vec<Vector> = serde_json::from_slice(&std::fs::read(VECTORS).unwrap()).unwrap();
+    assert_eq!(vectors.len(), test_vectors().len());
+
+    for (v, (name, _, _, bytes)) in vectors.iter().zip(test_vectors()) {
+        // Encoding has not changed, and decoding gives back the same message.
+        assert_eq!((v.name.as_str(), v.cbor.clone()), (name, to_hex(&bytes)));
+        let value: ciborium::value::Value = ciborium::de::from_reader(&bytes[..]).unwrap();
+        assert_eq!(v.diagnostic, diagnostic(&value));
+        let reencoded = match (v.kind.as_str(), v.ring_bits) {
+            ("user-input", None) => cbor::encode(&cbor::decode::<UserInput>(&bytes).unwrap()),
+            ("user-output", Some(64)) => cbor::encode(&cbor::decode::<UserOutput>(&bytes).unwrap()),
+            ("user-output", Some(16)) => cbor::encode(&cbor::decode::<UserOutput<Wrapping<u16>>>(&bytes).unwrap()),
+            _ => unreachable!(),
+        };
+        assert_eq!(reencoded.unwrap(), bytes);
+    }
+
+    // Other encodings of the same values are refused: an indefinite-length
+    // array, an integer longer than needed, keys out of order, trailing bytes.
+    let mut refused = vec![from_hex("9f00ff"), from_hex("811800")];
+    let mut value: ciborium::value::Value = ciborium::de::from_reader(&from_hex(&vectors[4].cbor)[..]).unwrap();
+    if let ciborium::value::Value::Map(entries) = &mut value.as_array_mut().unwrap()[1] {
+        entries.reverse();
+    }
+    refused.push(Vec::new());
+    ciborium::ser::into_writer(&value, refused.last_mut().unwrap()).unwrap();
+    refused.push([from_hex(&vectors[0].cbor), vec![0]].concat());
+    for bytes in refused {
+        assert_eq!(cbor::decode::<UserInput>(&bytes).err().unwrap().kind, ErrorKind::MalformedMessage);
+    }
+    // Neither is a masked input with elements out of its ring.
+    let out_of_range = cbor::encode(&UserOutput::Round2(vec![Wrapping(1u32 << 16)])).unwrap();
+    assert!(cbor::decode::<UserOutput<Wrapping<u16>>>(&out_of_range).is_err());
+}
+
+#[test]
+fn alive_users_must_take_part() {
+    setup();
+
+    let participants = 4;
+    let threshold = 3;
+    let vec_len = 2;
+
+    let sign_keys = (0..participants).map(|u| (u, gen_sign_keypair())).collect::<BTreeMap<_, _>>();
+    let sign_pks = Arc::new(sign_keys.iter().map(|(u, (pk, _))| (*u, *pk)).collect::<BTreeMap<_, _>>());
+    let mut users = sign_keys.into_iter().map(|(u, (sign_pk, sign_sk))| {
+        User::new(u, threshold, vec_len, sign_pk, sign_sk, vec![Wrapping(u as i64); vec_len], Arc::clone(&sign_pks), &integers()).unwrap()
+    }).collect::<Vec<User>>();
+    let mut server = Server::new(threshold, vec_len, Arc::clone(&sign_pks), &integers()).unwrap();
+
+    // User 3 does not send its shares, and so does not take part.
+    let mut msgs: BTreeMap<usize, UserInput> = users.iter().map(|u| (u.id(), UserInput::Round0())).collect();
+    for round in 0..3 {
+        for u in users.iter_mut() {
+            if round >= 1 && u.id() == 3 {
+                continue
+            }
+            if let Some(input) = msgs.remove(&u.id()) {
+                server.recv(u.id(), u.round(input).unwrap()).unwrap();
+            }
+        }
+        match server.round().unwrap() {
+            ServerOutput::Messages(m) => msgs = m,
+            _ => panic!("expected messages"),
+        }
+    }
+    assert!(matches!(msgs[&0], UserInput::Round3(_)));
+
+    // A server cannot claim it is alive in the consistency check.
+    let err = users[0].round(UserInput::Round3(vec![0, 1, 2, 3])).err().unwrap();
+    assert_eq!((err.user, err.kind), (Some(3), ErrorKind::UnknownUser));
+}
+
+#[test]
+fn failure_is_kept_in_state() {
+    setup();
+
+    let (sign_pk, sign_sk) = gen_sign_keypair();
+    let sign_pks = Arc::new(BTreeMap::from([(1, sign_pk)]));
+    let mut user = User::new(1, 1, 3, sign_pk, sign_sk, vec![Wrapping(0i64); 3], sign_pks, &integers()).unwrap();
+
+    let err = user.round(UserInput::Round3(vec![1])).err().unwrap();
+    assert_eq!(err.round, Some(0));
+    assert_eq!(err.kind, ErrorKind::UnexpectedRound);
+
+    // A failed user keeps reporting the error which caused the failure.
+    assert_eq!(user.round(UserInput::Round0()).err().unwrap(), err);
+}
+
+#[test]
+fn forged_advertisement_is_rejected() {
+    setup();
+
+    let participants = 6;
+    let threshold = 4;
+    let vec_len = 5;
+    let forger = 3;
+
+    let sign_keys = (0..participants).map(|u| (u, gen_sign_keypair())).collect::<BTreeMap<_, _>>();
+    let sign_pks = Arc::new(sign_keys.iter().map(|(u, (pk, _))| (*u, *pk)).collect::<BTreeMap<_, _>>());
+    let mut users = sign_keys.into_iter().map(|(u, (sign_pk, sign_sk))| {
+        User::new(u, threshold, vec_len, sign_pk, sign_sk, vec![Wrapping(u as i64); vec_len], Arc::clone(&sign_pks), &integers()).unwrap()
+    }).collect::<Vec<User>>();
+    let mut server = Server::new(threshold, vec_len, Arc::clone(&sign_pks), &integers()).unwrap();
+
+    let (_, wrong_sk) = gen_sign_keypair();
+    let mut msgs: BTreeMap<usize, UserInput> = users.iter().map(|u| (u.id(), UserInput::Round0())).collect();
+    let vec = loop {
+        for u in users.iter_mut() {
+            if let Some(input) = msgs.remove(&u.id()) {
+                match u.round(input).unwrap() {
+                    UserOutput::Round0(comm_pk, rand_pk) if u.id() == forger => {
+                        let forged = Signed::wrap(*comm_pk.msg(), &wrong_sk);
+                        let err = server.recv(u.id(), UserOutput::Round0(forged, rand_pk)).unwrap_err();
+                        assert_eq!(err.user, Some(forger));
+                        assert_eq!(err.kind, ErrorKind::BadSignature);
+                    },
+                    output => server.recv(u.id(), output).unwrap(),
+                }
+            }
+        }
+
+        match server.round().unwrap() {
+            ServerOutput::Messages(m) => msgs = m,
+            ServerOutput::Aggregate(aggregate) => break aggregate.vec,
+        }
+    };
+
+    let expected = (0..participants).filter(|u| *u != forger).map(|u| u as i64).sum::<i64>();
+    assert_eq!(vec, vec![Wrapping(expected); vec_len]);
+}
+
+#[test]
+fn shamir_beyond_255_participants() {
+    let ids = (0..1000).map(|u| 3 * u).collect::<Vec<usize>>();
+    let threshold = 600;
+    let secret = (0..32).collect::<Vec<u8>>();
+
+    let mut shares = shamir::share(&secret, threshold, &ids).unwrap().into_iter().collect::<Vec<_>>();
+    let mut rng = ChaCha8Rng::seed_from_u64(45);
+    shares.shuffle(&mut rng);
+
+    assert_eq!(shamir::reconstruct(&shares[..threshold], 32).unwrap(), secret);
+    assert_ne!(shamir::reconstruct(&shares[..threshold - 1], 32).unwrap(), secret);
+
+    // The coefficients of a set of responders serve for every secret they share.
+    let responders = shares[..threshold].iter().map(|(u, _)| *u).collect::<Vec<usize>>();
+    let interpolation = shamir::Interpolation::new(&responders).unwrap();
+    let other = [7; 32];
+    let other_shares = shamir::share(&other, threshold, &ids).unwrap();
+    let other_shares = responders.iter().map(|u| (*u, other_shares[u].clone())).collect::<Vec<_>>();
+    assert_eq!(interpolation.reconstruct(&shares[..threshold], 32).unwrap(), secret);
+    assert_eq!(interpolation.reconstruct(&other_shares, 32).unwrap(), other);
+}
+
+#[test]
+fn shamir_rejects_unsupported_parameters() {
+    let ids = [1, 2, 3];
+    let kind = |r: Result<_, AggregationError>| r.unwrap_err().kind;
+
+    assert_eq!(kind(shamir::check_parameters(0, &ids)), ErrorKind::InvalidParameters);
+    assert_eq!(kind(shamir::check_parameters(4, &ids)), ErrorKind::InvalidParameters);
+    assert_eq!(kind(shamir::check_parameters(2, &[1, usize::MAX])), ErrorKind::InvalidParameters);
+    assert!(shamir::check_parameters(3, &ids).is_ok());
+}
+
+#[test]
+fn shamir_shares_are_bound_to_ids() {
+    let ids = [4, 1000, 7, 123456];
+    let secret = [42; 32];
+
+    let shares = shamir::share(&secret, 3, &ids).unwrap();
+    let pairs = [123456, 4, 1000].into_iter().map(|u| (u, shares[&u].clone())).collect::<Vec<_>>();
+    assert_eq!(shamir::reconstruct(&pairs, 32).unwrap(), secret);
+
+    // A share presented under another id is rejected, and the holder blamed.
+    let swapped = vec![(4, shares[&7].clone()), (1000, shares[&1000].clone()), (7, shares[&4].clone())];
+    let err = shamir::reconstruct(&swapped, 32).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::MalformedMessage);
+    assert_eq!(err.user, Some(4));
+}
+
+#[test]
+fn missing_shares_are_skipped() {
+    setup();
+
+    let participants = 5;
+    let threshold = 3;
+    let vec_len = 4;
+    // User 1 leaves out its shares for user 2, user 3 leaves out a revealed share.
+    let (partial, sparing) = (1, 3);
+
+    let sign_keys = (0..participants).map(|u| (u, gen_sign_keypair())).collect::<BTreeMap<_, _>>();
+    let sign_pks = Arc::new(sign_keys.iter().map(|(u, (pk, _))| (*u, *pk)).collect::<BTreeMap<_, _>>());
+    let mut users = sign_keys.into_iter().map(|(u, (sign_pk, sign_sk))| {
+        User::new(u, threshold, vec_len, sign_pk, sign_sk, vec![Wrapping(u as i64); vec_len], Arc::clone(&sign_pks), &integers()).unwrap()
+    }).collect::<Vec<User>>();
+    let mut server = Server::new(threshold, vec_len, Arc::clone(&sign_pks), &integers()).unwrap();
+
+    let mut msgs: BTreeMap<usize, UserInput> = users.iter().map(|u| (u.id(), UserInput::Round0())).collect();
+    let aggregate = loop {
+        for u in users.iter_mut() {
+            if let Some(input) = msgs.remove(&u.id()) {
+                match u.round(input).unwrap() {
+                    UserOutput::Round1(mut shares) if u.id() == partial => {
+                        shares.remove(&2);
+                        server.recv(u.id(), UserOutput::Round1(shares)).unwrap();
+                    },
+                    UserOutput::Round4(mut shares) if u.id() == sparing => {
+                        shares.pop_first();
+                        server.recv(u.id(), UserOutput::Round4(shares)).unwrap();
+                    },
+                    output => server.recv(u.id(), output).unwrap(),
+                }
+            }
+        }
+
+        match server.round().unwrap() {
+            ServerOutput::Messages(m) => msgs = m,
+            ServerOutput::Aggregate(aggregate) => break aggregate,
+        }
+    };
+
+    // The partial user is left out, the others are unmasked from the remaining shares.
+    assert_eq!(aggregate.users, BTreeSet::from([0, 2, 3, 4]));
+    assert_eq!(aggregate.vec, vec![Wrapping(9); vec_len]);
+}
+
+#[test]
+fn fixed_point_encoding() {
+    let encoding = FixedPointEncoding::<Wrapping<u16>>::new(1000., 2.).unwrap();
+    assert_eq!(encoding.max_contributors(), 16);
+
+    let updates: Vec<Vec<f32>> = vec![
+        vec![0.5, -1.25, 3.0],
+        vec![-0.75, 0.001, -2.0],
+        vec![1.5, -1.999, 0.0],
+    ];
+    let sum = updates.iter()
+        .map(|v| encoding.encode(v).unwrap())
+        .fold(vec![Wrapping(0u16); 3], |acc, v| Iterator::zip(acc.iter(), v.iter()).map(|(a, b)| a + b).collect());
+    // 3.0 is clipped to 2.0.
+    let decoded: Vec<f32> = encoding.decode(&sum, updates.len()).unwrap();
+    assert_eq!(decoded, vec![1.25, -3.248, 0.0]);
+
+    assert_eq!(encoding.decode::<f64>(&sum, 17).unwrap_err().kind,
+        ErrorKind::TooManyContributors { max: 16, received: 17 });
+    assert_eq!(encoding.encode(&[f64::NAN]).unwrap_err().kind, ErrorKind::InvalidParameters);
+    assert!(FixedPointEncoding::<Wrapping<u32>>::new(1e6, 1e6).is_err());
+
+    // Weights and noise leave room for fewer contributors.
+    let weighted = encoding.clone().with_max_weight(2).unwrap();
+    assert_eq!(weighted.max_contributors(), 8);
+    let noise = NoiseConfig::new(Mechanism::Skellam, 1e6, 4).unwrap();
+    let noisy = weighted.clone().with_noise(noise);
+    assert_eq!(noisy.max_contributors(), 5);
+
+    // Which is checked when setting up a session.
+    setup();
+    let sign_pks = Arc::new((0..6).map(|u| (u, gen_sign_keypair().0)).collect::<BTreeMap<_, _>>());
+    assert!(Server::new(3, 3, Arc::clone(&sign_pks), &encoding).is_ok());
+    assert_eq!(Server::new(3, 3, Arc::clone(&sign_pks), &noisy).err().unwrap().kind,
+        ErrorKind::TooManyContributors { max: 5, received: 6 });
+    assert_eq!(Server::new_weighted(3, 3, Arc::clone(&sign_pks), &noisy).err().unwrap().kind,
+        ErrorKind::TooManyContributors { max: 5, received: 6 });
+    let user = |weight, encoding: &FixedPointEncoding<Wrapping<u16>>| {
+        let (sign_pk, sign_sk) = gen_sign_keypair();
+        User::new_weighted(0, 3, 3, sign_pk, sign_sk, vec![Wrapping(0u16); 3], Wrapping(weight), Arc::clone(&sign_pks), encoding)
+    };
+    assert!(user(2, &weighted).is_ok());
+    assert_eq!(user(3, &weighted).err().unwrap().kind, ErrorKind::InvalidParameters);
+    assert_eq!(user(2, &noisy).err().unwrap().kind, ErrorKind::TooManyContributors { max: 5, received: 6 });
+}
+
+#[test]
+fn noise_has_the_requested_variance() {
+    let mut rng = ChaCha8Rng::seed_from_u64(45);
+    for mechanism in [Mechanism::Skellam, Mechanism::DiscreteGaussian] {
+        let noise = NoiseConfig::new(mechanism, 400., 4).unwrap();
+        let samples = noise.sample(20000, &mut rng);
+        let mean = samples.iter().sum::<i64>() as f64 / 20000.;
+        let variance = samples.iter().map(|x| (*x as f64 - mean).powi(2)).sum::<f64>() / 20000.;
+        assert!(mean.abs() < 0.5, "{:?}: mean {}", mechanism, mean);
+        assert!((variance - 100.).abs() < 5., "{:?}: variance {}", mechanism, variance);
+    }
+    assert!(NoiseConfig::new(Mechanism::Skellam, 0., 4).is_err());
+    assert!(NoiseConfig::new(Mechanism::Skellam, 1., 0).is_err());
+    assert!(NoiseConfig::new(Mechanism::Skellam, f64::from_bits(1), 4).is_err());
+    // The sum of discrete Gaussians is only accounted for as such when each
+    // user adds a variance of at least 1.
+    assert!(NoiseConfig::new(Mechanism::DiscreteGaussian, 3., 4).is_err());
+    assert!(NoiseConfig::new(Mechanism::Skellam, 3., 4).is_ok());
+
+    // Configurations are checked when deserialized too.
+    let noise = NoiseConfig::new(Mechanism::DiscreteGaussian, 400., 4).unwrap();
+    let json = serde_json::to_value(&noise).unwrap();
+    assert_eq!(serde_json::from_value::<NoiseConfig>(json.clone()).unwrap(), noise);
+    let mut invalid = json.clone();
+    invalid["variance"] = (-1.).into();
+    assert!(serde_json::from_value::<NoiseConfig>(invalid).is_err());
+    let mut invalid = json;
+    invalid["variance"] = (3.).into();
+    assert!(serde_json::from_value::<NoiseConfig>(invalid).is_err());
+}
+
+#[test]
+fn noisy_aggregation() {
+    setup();
+
+    let participants = 6;
+    let threshold = 4;
+    let vec_len = 50;
+    let noise = NoiseConfig::new(Mechanism::Skellam, 100., participants).unwrap();
+    let encoding = integers().with_noise(noise);
+
+    let sign_keys = (0..participants).map(|u| (u, gen_sign_keypair())).collect::<BTreeMap<_, _>>();
+    let sign_pks = Arc::new(sign_keys.iter().map(|(u, (pk, _))| (*u, *pk)).collect::<BTreeMap<_, _>>());
+    let mut users = sign_keys.into_iter().map(|(u, (sign_pk, sign_sk))| {
+        User::new(u, threshold, vec_len, sign_pk, sign_sk, vec![Wrapping(1000i64); vec_len], Arc::clone(&sign_pks), &encoding).unwrap()
+    }).collect::<Vec<User>>();
+    let mut server = Server::new(threshold, vec_len, Arc::clone(&sign_pks), &encoding).unwrap();
+
+    let mut msgs: BTreeMap<usize, UserInput> = users.iter().map(|u| (u.id(), UserInput::Round0())).collect();
+    let vec = loop {
+        for u in users.iter_mut() {
+            if let Some(input) = msgs.remove(&u.id()) {
+                server.recv(u.id(), u.round(input).unwrap()).unwrap();
+            }
+        }
+
+        match server.round().unwrap() {
+            ServerOutput::Messages(m) => msgs = m,
+            ServerOutput::Aggregate(aggregate) => break aggregate.vec,
+        }
+    };
+
+    // The aggregate is off by the noise, whose standard deviation is 10.
+    let errors = vec.iter().map(|x| x.0 - 6000).collect::<Vec<i64>>();
+    assert!(errors.iter().all(|e| e.abs() < 80), "{:?}", errors);
+    assert!(errors.iter().any(|e| *e != 0));
+}
+
+#[test]
+fn privacy_accounting() {
+    let noise = NoiseConfig::new(Mechanism::Skellam, 1e4, 10).unwrap();
+    let accountant = Accountant::new(noise, 10., 100, 6).unwrap();
+
+    // Dropouts remove noise from the aggregate, and weaken the guarantee.
+    let eps = (6..=10).map(|n| accountant.epsilon(n, 1e-5).unwrap()).collect::<Vec<f64>>();
+    assert!(eps.windows(2).all(|w| w[0] > w[1]), "{:?}", eps);
+    assert_eq!(accountant.worst_case_epsilon(1e-5).unwrap(), eps[0]);
+    assert!(eps[4] > 0.4 && eps[4] < 0.6, "{:?}", eps);
+
+    // With the same variance, Skellam costs slightly more than the Gaussian.
+    let noise = NoiseConfig::new(Mechanism::DiscreteGaussian, 1e4, 10).unwrap();
+    let gaussian = Accountant::new(noise, 10., 100, 6).unwrap();
+    assert!(gaussian.epsilon(10, 1e-5).unwrap() < eps[4]);
+
+    assert_eq!(accountant.epsilon(5, 1e-5).unwrap_err().kind,
+        ErrorKind::BelowThreshold { threshold: 6, received: 5 });
+    assert_eq!(accountant.epsilon(8, 0.).unwrap_err().kind, ErrorKind::InvalidParameters);
+}